@@ -1,5 +1,5 @@
-use alloc::collections::BTreeMap;
-use super::{error, mir, mir::*};
+use alloc::collections::{BTreeMap, BTreeSet};
+use super::{error, mir, mir::*, target::{Overflow, Target}};
 use core::fmt;
 
 use lalrpop_util::lalrpop_mod;
@@ -52,16 +52,198 @@ fn assign_var(var: String, op: String, val: Expr) -> Expr {
     }))
 }
 
+/// Define a generic function, and evaluate `result` with it in scope
+fn define_generic(name: String, params: Vec<String>, args: Vec<(Pattern, Type)>, ret: Type, body: Expr, result: Expr) -> Expr {
+    Expr::LetInfer(
+        name,
+        Box::new(Expr::Generic(params, Box::new(Expr::Function(vec![], args, ret, Box::new(body))))),
+        Box::new(result)
+    )
+}
+
+/// Define a function, and evaluate `result` with it in scope.
+/// If the function captures any variables, they're stored in an
+/// environment tuple next to the function definition.
+fn define_function(name: String, captures: Vec<Capture>, args: Vec<(Pattern, Type)>, ret: Type, body: Expr, result: Expr) -> Expr {
+    let t = Type::Function(
+        args.iter().map(|(_, t)| t.clone()).collect(),
+        Box::new(ret.clone())
+    );
+    let env = Expr::Tuple(captures.iter().map(Capture::to_expr).collect());
+    let function = Expr::Let(
+        name.clone(),
+        t,
+        Box::new(Expr::Function(captures.clone(), args, ret, Box::new(body))),
+        Box::new(result)
+    );
+
+    if captures.is_empty() {
+        function
+    } else {
+        Expr::LetInfer(env_name(&name), Box::new(env), Box::new(function))
+    }
+}
+
+/// The name of the environment of captured variables for a function
+fn env_name(function: &str) -> String {
+    format!("{}.env", function)
+}
+
+/// The name of the macro that pushes the index of a function definition
+fn index_name(function: &str) -> String {
+    format!("{}.index", function)
+}
+
+/// The name of the macro that pushes the address of a global variable.
+/// Globals have their own macro so that local variables in a function's
+/// caller can't shadow them.
+fn global_name(global: &str) -> String {
+    format!("{}.global", global)
+}
+
+/// The name of the entry that marks a global as a constant
+fn const_name(global: &str) -> String {
+    format!("{}.const", global)
+}
+
+/// Add a variable to the scope. Function definitions also add an entry
+/// for their index, and closures keep the entry for their environment.
+fn bind(scope: &BTreeMap<String, Type>, name: &str, t: &Type, val: &Expr) -> BTreeMap<String, Type> {
+    let mut scope = scope.clone();
+    scope.insert(name.to_string(), t.clone());
+    scope.remove(&index_name(name));
+    scope.remove(&global_name(name));
+    scope.remove(&const_name(name));
+    match val {
+        Expr::Function(captures, _, _, _) if captures.is_empty() => {
+            scope.remove(&env_name(name));
+            scope.insert(index_name(name), Type::Integer);
+        }
+        Expr::Function(_, _, _, _) => {}
+        _ => { scope.remove(&env_name(name)); }
+    }
+    scope
+}
+
+/// The instances of a generic function that have been checked so far.
+/// They're all defined where the generic function is.
+struct Instances {
+    name: String,
+    /// The scope the generic function is defined in
+    scope: BTreeMap<String, Type>,
+    /// The name and definition of each instance, which is `None` while it's being checked
+    functions: Vec<(String, Option<Typed>)>,
+}
+
+/// Add a global variable to the scope, which can be used in functions
+fn bind_global(scope: &BTreeMap<String, Type>, name: &str, t: &Type, constant: bool) -> BTreeMap<String, Type> {
+    let mut scope = bind(scope, name, t, &Expr::None);
+    scope.insert(global_name(name), Type::Pointer(Box::new(t.clone())));
+    if constant {
+        scope.insert(const_name(name), t.clone());
+    }
+    scope
+}
+
+/// The name of the macro that pushes the address of a variable
+fn address_name(scope: &BTreeMap<String, Type>, name: &str) -> String {
+    if scope.contains_key(&global_name(name)) {
+        global_name(name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// Store a variable on the stack at an address, and evaluate a body
+/// with it in scope. The result of the body is moved down to where
+/// the variable was stored, so the variable is freed.
+fn store_variable(name: &str, size: u32, address: Vec<Op>, value: Op, body: Op, result_size: u32) -> Op {
+    Op::Let(name.to_string(), address, vec![
+        // Allocate space on the stack to
+        // store the value
+        Op::Stalloc(size),
+        // Store the value
+        value,
+        Op::Macro(name.to_string()),
+        Op::Store(size),
+
+        body,
+
+        // Remove the variable from beneath the result
+        Op::Slide(result_size, size),
+    ])
+}
+
+/// The value a cell ends up with after an arithmetic operation with the exact
+/// result `n`, the way the target handles overflow. Returns `None` if the
+/// operation traps.
+fn overflow(n: i128, target: &Target) -> Option<u64> {
+    let max = target.cell_width.max_value() as i128;
+    let n = if (0..=max).contains(&n) {
+        n
+    } else {
+        match target.overflow {
+            Overflow::Wrap => n.rem_euclid(max + 1),
+            Overflow::Saturate if n < 0 => 0,
+            Overflow::Saturate => max,
+            Overflow::Trap => return None,
+        }
+    };
+    u64::try_from(n).ok()
+}
+
+/// Make a literal for an expression, making sure it fits in a cell on the target
+fn literal(expr: &Expr, n: u64, target: &Target) -> Result<Literal, Error> {
+    if target.fits(n) {
+        Ok(Literal(n))
+    } else {
+        Err(Error::LiteralOutOfRange(expr.clone(), *target))
+    }
+}
+
+/// The cells a value is made of, if its code only pushes literals
+fn constant_cells(code: &Op) -> Option<Vec<Literal>> {
+    match code {
+        Op::PushLiteral(n) => Some(vec![*n]),
+        Op::Do(code) => {
+            let mut cells = vec![];
+            for op in code {
+                cells.extend(constant_cells(op)?);
+            }
+            Some(cells)
+        }
+        _ => None,
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Error {
     VariableNotInScope(String),
     CallNonFunction(String),
-    MismatchedTypes(Expr, Type, Type),
-    SizeOfFunction(Type),
-    DerefNonPointer(Expr, Type),
-    NthOfNonTuple(Expr, Type),
+    MismatchedTypes(Box<Expr>, Type, Type),
+    DerefNonPointer(Box<Expr>, Type),
+    NthOfNonTuple(Box<Expr>, Type),
+    PatternMismatch(Pattern, Type),
     AllocVoid(Expr),
-    CmpOfTuple(Expr, Type),
+    NonConstantArray(Expr),
+    EmptyArray(Expr),
+    LiteralOutOfRange(Expr, Target),
+    VariableNotCaptured(String),
+    ClosureInFunction(String),
+    ClosureAsValue(String),
+    GenericAsValue(String),
+    ImportNotFound(String),
+    ImportCycle(Vec<String>),
+    NotExported(String, String),
+    CannotInfer(Expr, String),
+    UnknownType(String),
+    FunctionNotVariable(String),
+    InvalidCast(Box<Expr>, Type, Type),
+    ConstNotVariable(String),
+    RecursiveDefinition(Vec<String>),
+    DuplicateName(String),
+    FunctionEscapes(String),
+    NoEntryPoint,
 
     ParseError(String),
     MIRError(mir::Error)
@@ -73,11 +255,29 @@ impl fmt::Display for Error {
             Self::VariableNotInScope(name) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mvariable `{}` is used, but not in scope", name),
             Self::CallNonFunction(name) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mcalled non-function `{}`", name),
             Self::MismatchedTypes(expr, expected, found) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mmismatched types: expected `{}` but found `{}` in expression `{}`", expected, found, expr),
-            Self::SizeOfFunction(t) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mattempted to get the size of a function with signature `{}`: are you trying to assign functions to a value?", t),
             Self::DerefNonPointer(expr, t) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mdereferenced non-pointer type `{}` in expression `{}`", t, expr),
             Self::NthOfNonTuple(expr, t) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mmember of non-tuple type `{}` in expression `{}`", t, expr),
+            Self::PatternMismatch(pattern, t) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mpattern `{}` does not match type `{}`", pattern, t),
             Self::AllocVoid(expr) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mattempted to allocate an array of elements with type `void` in expression `{}`", expr),
-            Self::CmpOfTuple(expr, t) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mattempted to compare tuple of type `{}` in expression `{}`", t, expr),
+            Self::NonConstantArray(expr) => write!(f, "\x1b[91merror: \x1b[m\x1b[0marray literals can only contain constants, but found `{}`", expr),
+            Self::EmptyArray(expr) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mcannot infer the type of the empty array `{}`", expr),
+            Self::LiteralOutOfRange(expr, target) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mliteral `{}` does not fit in a cell on a {} target", expr, target),
+            Self::VariableNotCaptured(name) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mvariable `{}` is used in a function, but not captured: try adding it to the function's capture list, like `fn f[{}](...)` or `fn f[&{}](...)`", name, name, name),
+            Self::ClosureInFunction(name) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mclosure `{}` is used in another function, but closures can only be called in the scope they're defined in", name),
+            Self::ClosureAsValue(name) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mclosure `{}` is used as a value, but only functions that don't capture variables can be passed around", name),
+            Self::ImportNotFound(path) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mcould not find imported file `{}`", path),
+            Self::ImportCycle(paths) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mimport cycle: {}", paths.join(" imports ")),
+            Self::NotExported(name, path) => write!(f, "\x1b[91merror: \x1b[m\x1b[0m`{}` is imported, but not defined in `{}`", name, path),
+            Self::GenericAsValue(name) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mgeneric function `{}` is used as a value, but it can only be called", name),
+            Self::CannotInfer(expr, param) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mcannot infer type parameter `{}` from the arguments in expression `{}`", param, expr),
+            Self::UnknownType(name) => write!(f, "\x1b[91merror: \x1b[m\x1b[0munknown type `{}`", name),
+            Self::FunctionNotVariable(name) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mfunction `{}` is a definition, not a variable: it can't be assigned to or referenced", name),
+            Self::ConstNotVariable(name) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mconstant `{}` can't be assigned to or referenced", name),
+            Self::RecursiveDefinition(names) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mrecursive definition: {}", names.join(" uses ")),
+            Self::FunctionEscapes(name) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mfunction `{}` is used as a value outside of the scope it's defined in, where it can't be called", name),
+            Self::DuplicateName(name) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mthe name `{}` is bound more than once in the same pattern or argument list", name),
+            Self::NoEntryPoint => write!(f, "\x1b[91merror: \x1b[m\x1b[0mthe program has no `main` function or expression to run"),
+            Self::InvalidCast(expr, from, to) => write!(f, "\x1b[91merror: \x1b[m\x1b[0mcannot cast from `{}` to `{}` in expression `{}`", from, to, expr),
 
             Self::ParseError(e) => write!(f, "\x1b[91merror: \x1b[m\x1b[0m\n{}", e),
             Self::MIRError(e) => write!(f, "{}", e)
@@ -101,9 +301,116 @@ pub fn parse(code: String) -> Result<Expr, Error> {
     }
 }
 
+/// A file of top level declarations, followed by an optional expression to run
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Module {
+    pub items: Vec<Item>,
+    pub body: Option<Expr>,
+}
+
+/// The names an import brings into scope
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Import {
+    /// Every function in the file
+    All,
+    /// Every function in the file, prefixed with a module name like `name::function`
+    Qualified(String),
+    /// Only the listed functions
+    Only(Vec<String>),
+}
+
+/// A top level declaration
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Item {
+    Import(String, Import),
+    /// A function with its name, type parameters, arguments, return type, and body
+    Function(String, Vec<String>, Vec<(Pattern, Type)>, Type, Expr),
+    /// A constant, which can be used in every function
+    Const(String, Type, Expr),
+    /// A global variable, which can be used in every function
+    Global(String, Type, Expr),
+}
+
+impl Item {
+    /// Define this item, and evaluate `result` with it in scope
+    pub fn define(self, result: Expr) -> Expr {
+        match self {
+            Self::Import(_, _) => result,
+            Self::Function(name, params, args, ret, body) if params.is_empty() => {
+                define_function(name, vec![], args, ret, body, result)
+            }
+            Self::Function(name, params, args, ret, body) => {
+                define_generic(name, params, args, ret, body, result)
+            }
+            Self::Const(name, t, val) => Expr::Const(name, t, Box::new(val), Box::new(result)),
+            Self::Global(name, t, val) => Expr::Global(name, t, Box::new(val), Box::new(result)),
+        }
+    }
+
+    /// The name this item defines, if it defines one
+    pub fn name(&self) -> Option<&String> {
+        match self {
+            Self::Import(_, _) => None,
+            Self::Function(name, _, _, _, _)
+            | Self::Const(name, _, _)
+            | Self::Global(name, _, _) => Some(name),
+        }
+    }
+
+    /// Rename the free variables in this item's definition
+    pub fn rename(self, names: &BTreeMap<String, String>) -> Self {
+        match self {
+            Self::Import(_, _) => self,
+            Self::Function(name, params, args, ret, body) => {
+                let mut names = names.clone();
+                for arg in args.iter().flat_map(|(arg, _)| arg.names()) {
+                    names.remove(arg);
+                }
+                let body = body.rename(&names);
+                Self::Function(name, params, args, ret, body)
+            }
+            Self::Const(name, t, val) => Self::Const(name, t, val.rename(names)),
+            Self::Global(name, t, val) => Self::Global(name, t, val.rename(names)),
+        }
+    }
+
+    /// The names this item's definition uses, other than its arguments
+    pub fn uses(&self) -> BTreeSet<String> {
+        match self {
+            Self::Import(_, _) => BTreeSet::new(),
+            Self::Function(_, _, args, _, body) => {
+                let mut names = body.free_names();
+                for arg in args.iter().flat_map(|(arg, _)| arg.names()) {
+                    names.remove(arg);
+                }
+                names
+            }
+            Self::Const(_, _, val) | Self::Global(_, _, val) => val.free_names(),
+        }
+    }
+}
+
+pub fn parse_module(code: String) -> Result<Module, Error> {
+    let code = match comment::c::strip(&code) {
+        Ok(s) => s,
+        Err(_) => code
+    };
+    
+    match hir_parser::ModuleParser::new().parse(&code) {
+        Ok(parsed) => {
+            Ok(parsed)
+        },
+        Err(e) => {
+            Err(Error::ParseError(error::format_error(&code, e)))
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Type {
     Integer,
+    /// A signed integer, stored as a sign cell followed by a magnitude cell
+    Signed,
     Bool,
     Character,
     Void,
@@ -113,6 +420,11 @@ pub enum Type {
 
     // Tuple(Vec<Self>),
     Function(Vec<Self>, Box<Self>),
+
+    /// A type parameter of a generic function
+    Var(String),
+    /// A generic function, which is specialized for its arguments when called
+    Generic(Vec<String>, Box<Expr>),
 }
 
 impl fmt::Display for Type {
@@ -126,6 +438,7 @@ impl fmt::Display for Type {
                 write!(f, ")")
             },
             Self::Integer => write!(f, "int"),
+            Self::Signed => write!(f, "signed"),
             Self::Bool => write!(f, "bool"),
             Self::Character => write!(f, "char"),
             Self::Void => write!(f, "void"),
@@ -137,6 +450,22 @@ impl fmt::Display for Type {
                 }
                 write!(f, ") -> {}", ret)
             },
+            Self::Var(name) => write!(f, "{}", name),
+            Self::Generic(params, function) => {
+                write!(f, "<")?;
+                for param in params {
+                    write!(f, "{}, ", param)?;
+                }
+                write!(f, ">")?;
+                if let Expr::Function(_, args, ret, _) = &**function {
+                    write!(f, "(")?;
+                    for (_, t) in args {
+                        write!(f, "{}, ", t)?;
+                    }
+                    write!(f, ") -> {}", ret)?;
+                }
+                Ok(())
+            },
         }
     }
 }
@@ -149,9 +478,11 @@ impl Type {
             | Self::Character => 1,
             Self::Void => 0,
             Self::Pointer(_) => 1,
-            Self::Function(_, _) => {
-                return Err(Error::SizeOfFunction(self.clone()))
-            }
+            Self::Signed => 2,
+            // Function values are an index into the functions with the same signature
+            Self::Function(_, _) => 1,
+            Self::Var(name) => return Err(Error::UnknownType(name.clone())),
+            Self::Generic(_, _) => return Err(Error::GenericAsValue(self.to_string())),
             Self::Tuple(items) => {
                 let mut size = 0;
                 for item in items {
@@ -162,6 +493,68 @@ impl Type {
         })
     }
 
+    /// Is this type, or any type it's made of, the type `t`?
+    fn contains(&self, t: &Self) -> bool {
+        self == t || match self {
+            Self::Pointer(inner) => inner.contains(t),
+            Self::Tuple(items) => items.iter().any(|item| item.contains(t)),
+            Self::Function(args, ret) => args.iter().any(|arg| arg.contains(t)) || ret.contains(t),
+            _ => false,
+        }
+    }
+
+    /// Replace type parameters with the types they're bound to
+    fn substitute(&self, types: &BTreeMap<String, Self>) -> Self {
+        match self {
+            Self::Var(name) => types.get(name).cloned().unwrap_or_else(|| self.clone()),
+            Self::Pointer(inner) => Self::Pointer(Box::new(inner.substitute(types))),
+            Self::Tuple(items) => Self::Tuple(items.iter().map(|t| t.substitute(types)).collect()),
+            Self::Function(args, ret) => Self::Function(
+                args.iter().map(|t| t.substitute(types)).collect(),
+                Box::new(ret.substitute(types))
+            ),
+            Self::Generic(params, function) => {
+                let mut types = types.clone();
+                for param in params {
+                    types.remove(param);
+                }
+                Self::Generic(params.clone(), Box::new(function.substitute(&types)))
+            }
+            _ => self.clone(),
+        }
+    }
+
+    /// Match this type against a concrete type, binding its type parameters.
+    /// Returns false if the types can't be made the same.
+    fn unify(&self, concrete: &Self, types: &mut BTreeMap<String, Self>) -> bool {
+        match (self, concrete) {
+            (Self::Var(name), _) => {
+                if let Some(t) = types.get(name) {
+                    t == concrete
+                } else {
+                    types.insert(name.clone(), concrete.clone());
+                    true
+                }
+            }
+            (Self::Pointer(a), Self::Pointer(b)) => a.unify(b, types),
+            (Self::Tuple(a), Self::Tuple(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.unify(b, types))
+            }
+            (Self::Function(a_args, a_ret), Self::Function(b_args, b_ret)) => {
+                a_args.len() == b_args.len()
+                    && a_args.iter().zip(b_args).all(|(a, b)| a.unify(b, types))
+                    && a_ret.unify(b_ret, types)
+            }
+            (a, b) => a == b,
+        }
+    }
+
+    /// Is this a type that fits in a single cell, and can be
+    /// freely cast to other scalars?
+    fn is_scalar(&self) -> bool {
+        matches!(self, Self::Integer | Self::Bool | Self::Character)
+    }
+
     fn nth(&self, n: u32) -> Option<(&Self, u32)> {
         if let Self::Tuple(items) = self {
             if (n as usize) < items.len() {
@@ -180,16 +573,117 @@ impl Type {
     }
 }
 
+/// A variable captured by a closure
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Capture {
+    /// Copy the variable into the closure when it's defined
+    Value(String),
+    /// Store the variable's address in the closure
+    Reference(String),
+}
+
+impl Capture {
+    pub fn name(&self) -> &String {
+        match self {
+            Self::Value(name) | Self::Reference(name) => name
+        }
+    }
+
+    /// The expression that gets stored in the closure's environment
+    fn to_expr(&self) -> Expr {
+        match self {
+            Self::Value(name) => Expr::Variable(name.clone()),
+            Self::Reference(name) => Expr::Refer(name.clone()),
+        }
+    }
+}
+
+impl fmt::Display for Capture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Value(name) => write!(f, "{}", name),
+            Self::Reference(name) => write!(f, "&{}", name),
+        }
+    }
+}
+
+/// What a `let` or a function argument binds: a name, or a tuple of patterns
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Pattern {
+    Name(String),
+    Tuple(Vec<Self>),
+}
+
+impl Pattern {
+    /// The names the pattern binds
+    pub fn names(&self) -> Vec<&String> {
+        match self {
+            Self::Name(name) => vec![name],
+            Self::Tuple(items) => items.iter().flat_map(Self::names).collect(),
+        }
+    }
+
+    /// The variables the pattern binds for a value of type `t`, in the order
+    /// their cells are stored in the value
+    fn bindings(&self, t: &Type) -> Result<Vec<(String, Type)>, Error> {
+        match (self, t) {
+            (Self::Name(name), t) => Ok(vec![(name.clone(), t.clone())]),
+            (Self::Tuple(items), Type::Tuple(types)) if items.len() == types.len() => {
+                let mut result = vec![];
+                for (item, t) in items.iter().zip(types) {
+                    result.extend(item.bindings(t)?);
+                }
+                Ok(result)
+            }
+            _ => Err(Error::PatternMismatch(self.clone(), t.clone())),
+        }
+    }
+}
+
+/// Make sure no name is bound twice by the same patterns
+fn unique_names<'a>(names: impl IntoIterator<Item = &'a String>) -> Result<(), Error> {
+    let mut seen = BTreeSet::new();
+    for name in names {
+        if !seen.insert(name) {
+            return Err(Error::DuplicateName(name.clone()));
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "{}", name),
+            Self::Tuple(items) => {
+                write!(f, "(")?;
+                for item in items {
+                    write!(f, "{}, ", item)?
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Expr {
-    Integer(u32),
+    Integer(u64),
+    Signed(i64),
     Bool(bool),
     Character(char),
     None,
 
-    Function(Vec<(String, Type)>, Type, Box<Self>),
+    Function(Vec<Capture>, Vec<(Pattern, Type)>, Type, Box<Self>),
+    Generic(Vec<String>, Box<Self>),
     Let(String, Type, Box<Self>, Box<Self>),
     LetInfer(String, Box<Self>, Box<Self>),
+    /// Bind the members of a tuple to the names in a pattern, with an optional type
+    Destructure(Pattern, Option<Type>, Box<Self>, Box<Self>),
+    /// A variable stored at a fixed address, which can be used in any function
+    Global(String, Type, Box<Self>, Box<Self>),
+    /// A global variable that can't be changed
+    Const(String, Type, Box<Self>, Box<Self>),
     Assign(String, Box<Self>),
     
     Call(String, Vec<Self>),
@@ -216,14 +710,26 @@ pub enum Expr {
     Or(Box<Self>, Box<Self>),
     And(Box<Self>, Box<Self>),
     Not(Box<Self>),
+    Cast(Box<Self>, Type),
 
     Getchar,
     Getnum,
+    GetSigned,
     Putchar(Box<Self>),
     Putnum(Box<Self>),
 
     Free(Box<Self>),
     Alloc(Box<Self>, Type, Option<Vec<Self>>),
+    /// Resize an allocation to a number of values, keeping its contents
+    Realloc(Box<Self>, Box<Self>),
+    /// The number of cells a value of a type takes
+    SizeOf(Type),
+    /// Copy a number of values from one pointer to another
+    Memcpy(Box<Self>, Box<Self>, Box<Self>),
+    /// Store a value at the start of a buffer a number of times
+    Memset(Box<Self>, Box<Self>, Box<Self>),
+    /// An array of constants, which is stored once in the program's static data
+    Array(Vec<Self>),
 
     Block(Vec<Self>),
 
@@ -241,6 +747,8 @@ impl fmt::Display for Expr {
             Self::Decrement(name) => write!(f, "{}--", name),
 
             Self::Integer(i) => write!(f, "{}", i),
+            Self::Signed(i) if *i < 0 => write!(f, "{}", i),
+            Self::Signed(i) => write!(f, "({} as signed)", i),
             Self::Bool(x) => write!(f, "{}", x),
             Self::Character(ch) => write!(f, "{:?}", ch),
             Self::Tuple(items) => {
@@ -254,15 +762,31 @@ impl fmt::Display for Expr {
 
             Self::None => write!(f, "()"),
 
-            Self::Function(args, ret, body) => {
-                write!(f, "fn(")?;
-                for (name, t) in args {
-                    write!(f, "{}: {}, ", name, t)?;
+            Self::Function(captures, args, ret, body) => {
+                write!(f, "fn")?;
+                if !captures.is_empty() {
+                    write!(f, "[")?;
+                    for capture in captures {
+                        write!(f, "{}, ", capture)?;
+                    }
+                    write!(f, "]")?;
+                }
+                write!(f, "(")?;
+                for (pattern, t) in args {
+                    write!(f, "{}: {}, ", pattern, t)?;
                 }
                 write!(f, ") -> {} = {}", ret, body)
             }
 
             
+            Self::Generic(params, function) => {
+                write!(f, "<")?;
+                for param in params {
+                    write!(f, "{}, ", param)?;
+                }
+                write!(f, "> {}", function)
+            }
+
             Self::Let(name, t, val, ret) => {
                 write!(f, "let {}: {} = {} in {}", name, t, val, ret)
             }
@@ -271,6 +795,22 @@ impl fmt::Display for Expr {
                 write!(f, "let {} = {} in {}", name, val, ret)
             }
 
+            Self::Destructure(pattern, Some(t), val, ret) => {
+                write!(f, "let {}: {} = {} in {}", pattern, t, val, ret)
+            }
+
+            Self::Destructure(pattern, None, val, ret) => {
+                write!(f, "let {} = {} in {}", pattern, val, ret)
+            }
+
+            Self::Global(name, t, val, ret) => {
+                write!(f, "let {}: {} = {}; {}", name, t, val, ret)
+            }
+
+            Self::Const(name, t, val, ret) => {
+                write!(f, "const {}: {} = {}; {}", name, t, val, ret)
+            }
+
             Self::Assign(name, val) => 
                 write!(f, "{} = {}", name, val),
 
@@ -299,6 +839,7 @@ impl fmt::Display for Expr {
             Self::And(lhs, rhs) => write!(f, "{} && {}", lhs, rhs),
             Self::Or(lhs, rhs) => write!(f, "{} || {}", lhs, rhs),
             Self::Not(x) => write!(f, "!{}", x),
+            Self::Cast(x, t) => write!(f, "({} as {})", x, t),
 
             Self::Eq(lhs, rhs) => write!(f, "({} == {})", lhs, rhs),
             Self::Neq(lhs, rhs) => write!(f, "({} != {})", lhs, rhs),
@@ -307,6 +848,7 @@ impl fmt::Display for Expr {
             Self::Putnum(x) => write!(f, "putnum({})", x),
             Self::Getchar => write!(f, "getchar()"),
             Self::Getnum => write!(f, "getnum()"),
+            Self::GetSigned => write!(f, "getnum(signed)"),
 
             Self::Alloc(n, t, vals) => {
                 if let Some(vals) = vals {
@@ -320,6 +862,24 @@ impl fmt::Display for Expr {
                 }
             },
             Self::Free(x) => write!(f, "free({})", x),
+            Self::Realloc(ptr, n) => write!(f, "realloc({}, {})", ptr, n),
+            Self::SizeOf(t) => write!(f, "sizeof({})", t),
+            Self::Memcpy(dst, src, n) => write!(f, "memcpy({}, {}, {})", dst, src, n),
+            Self::Memset(dst, val, n) => write!(f, "memset({}, {}, {})", dst, val, n),
+            Self::Array(items) => match items.split_last() {
+                // Strings are arrays of characters ending with a null character
+                Some((Self::Character('\0'), chars)) if chars.iter().all(|x| matches!(x, Self::Character(_))) => {
+                    let s = chars.iter().map(|x| if let Self::Character(ch) = x { *ch } else { unreachable!() }).collect::<String>();
+                    write!(f, "{:?}", s)
+                }
+                _ => {
+                    write!(f, "[")?;
+                    for item in items {
+                        write!(f, "{}, ", item)?;
+                    }
+                    write!(f, "]")
+                }
+            },
 
             Self::Block(block) => {
                 write!(f, "do ")?;
@@ -339,598 +899,867 @@ impl fmt::Display for Expr {
 }
 
 impl Expr {
-    pub fn compile(&self, scope: &BTreeMap<String, Type>, offset: &mut u32) -> Result<Op, Error> {
-        self.type_check(scope)?;
-        Ok(match self {
-            Self::Tuple(items) => {
-                let mut result = vec![];
-                for item in items {
-                    result.push(item.compile(scope, offset)?)
+    /// Replace the type parameters in an expression with the types they're bound to
+    fn substitute(&self, types: &BTreeMap<String, Type>) -> Self {
+        let sub = |x: &Self| Box::new(x.substitute(types));
+        match self {
+            Self::Integer(_)
+            | Self::Signed(_)
+            | Self::Bool(_)
+            | Self::Character(_)
+            | Self::None
+            | Self::Variable(_)
+            | Self::Increment(_)
+            | Self::Decrement(_)
+            | Self::Refer(_)
+            | Self::Getchar
+            | Self::Getnum
+            | Self::GetSigned => self.clone(),
+
+            Self::Function(captures, args, ret, body) => Self::Function(
+                captures.clone(),
+                args.iter().map(|(pattern, t)| (pattern.clone(), t.substitute(types))).collect(),
+                ret.substitute(types),
+                sub(body)
+            ),
+            Self::Generic(params, function) => {
+                let mut types = types.clone();
+                for param in params {
+                    types.remove(param);
                 }
-                Op::Do(result)
+                Self::Generic(params.clone(), Box::new(function.substitute(&types)))
             }
+            Self::Let(name, t, val, body) => Self::Let(name.clone(), t.substitute(types), sub(val), sub(body)),
+            Self::LetInfer(name, val, body) => Self::LetInfer(name.clone(), sub(val), sub(body)),
+            Self::Destructure(pattern, t, val, body) => Self::Destructure(
+                pattern.clone(),
+                t.as_ref().map(|t| t.substitute(types)),
+                sub(val),
+                sub(body)
+            ),
+            Self::Global(name, t, val, body) => Self::Global(name.clone(), t.substitute(types), sub(val), sub(body)),
+            Self::Const(name, t, val, body) => Self::Const(name.clone(), t.substitute(types), sub(val), sub(body)),
+            Self::Assign(name, val) => Self::Assign(name.clone(), sub(val)),
+            Self::Call(name, args) => Self::Call(name.clone(), args.iter().map(|x| x.substitute(types)).collect()),
+
+            Self::Deref(x) => Self::Deref(sub(x)),
+            Self::DerefAssign(a, b) => Self::DerefAssign(sub(a), sub(b)),
+            Self::Tuple(items) => Self::Tuple(items.iter().map(|x| x.substitute(types)).collect()),
+            Self::Nth(x, n) => Self::Nth(sub(x), *n),
+            Self::ReferIndex(a, b) => Self::ReferIndex(sub(a), sub(b)),
+            Self::Index(a, b) => Self::Index(sub(a), sub(b)),
+            Self::IndexAssign(a, b, c) => Self::IndexAssign(sub(a), sub(b), sub(c)),
+
+            Self::Add(a, b) => Self::Add(sub(a), sub(b)),
+            Self::Sub(a, b) => Self::Sub(sub(a), sub(b)),
+            Self::Mul(a, b) => Self::Mul(sub(a), sub(b)),
+            Self::Div(a, b) => Self::Div(sub(a), sub(b)),
+            Self::Or(a, b) => Self::Or(sub(a), sub(b)),
+            Self::And(a, b) => Self::And(sub(a), sub(b)),
+            Self::Not(x) => Self::Not(sub(x)),
+            Self::Cast(x, t) => Self::Cast(sub(x), t.substitute(types)),
+
+            Self::Putchar(x) => Self::Putchar(sub(x)),
+            Self::Putnum(x) => Self::Putnum(sub(x)),
+            Self::Free(x) => Self::Free(sub(x)),
+            Self::Realloc(ptr, n) => Self::Realloc(sub(ptr), sub(n)),
+            Self::SizeOf(t) => Self::SizeOf(t.substitute(types)),
+            Self::Memcpy(dst, src, n) => Self::Memcpy(sub(dst), sub(src), sub(n)),
+            Self::Memset(dst, val, n) => Self::Memset(sub(dst), sub(val), sub(n)),
+            Self::Alloc(n, t, vals) => Self::Alloc(
+                sub(n),
+                t.substitute(types),
+                vals.as_ref().map(|vals| vals.iter().map(|x| x.substitute(types)).collect())
+            ),
+            Self::Array(items) => Self::Array(items.iter().map(|x| x.substitute(types)).collect()),
+
+            Self::Block(items) => Self::Block(items.iter().map(|x| x.substitute(types)).collect()),
+            Self::While(a, b) => Self::While(sub(a), sub(b)),
+            Self::If(a, b) => Self::If(sub(a), sub(b)),
+            Self::Eq(a, b) => Self::Eq(sub(a), sub(b)),
+            Self::Neq(a, b) => Self::Neq(sub(a), sub(b)),
+        }
+    }
 
-            Self::Increment(name) => {
-                Op::Do(vec![
-                    Expr::Refer(name.clone()).compile(scope, offset)?,
-                    Op::Increment(SP.deref().deref(), 1),
-                    // Op::Load(Type::Integer.get_size()?)
-                    Op::Stfree(1),
-                ])
+    /// Rename the free variables in an expression. Variables that
+    /// are shadowed by a `let` or a function argument are left alone.
+    pub fn rename(&self, names: &BTreeMap<String, String>) -> Self {
+        let name = |name: &String| names.get(name).cloned().unwrap_or_else(|| name.clone());
+        let sub = |x: &Self| Box::new(x.rename(names));
+        let without = |shadowed: &[&String]| {
+            let mut names = names.clone();
+            for name in shadowed {
+                names.remove(*name);
             }
-            
-            Self::Decrement(name) => {
-                Op::Do(vec![
-                    Expr::Refer(name.clone()).compile(scope, offset)?,
-                    Op::Decrement(SP.deref().deref(), 1),
-                    // Op::Load(Type::Integer.get_size()?)
-                    Op::Stfree(1),
-                ])
+            names
+        };
+        match self {
+            Self::Integer(_)
+            | Self::Signed(_)
+            | Self::Bool(_)
+            | Self::Character(_)
+            | Self::None
+            | Self::Getchar
+            | Self::Getnum
+            | Self::GetSigned => self.clone(),
+
+            Self::Variable(x) => Self::Variable(name(x)),
+            Self::Increment(x) => Self::Increment(name(x)),
+            Self::Decrement(x) => Self::Decrement(name(x)),
+            Self::Refer(x) => Self::Refer(name(x)),
+            Self::Assign(x, val) => Self::Assign(name(x), sub(val)),
+            Self::Call(f, args) => Self::Call(name(f), args.iter().map(|x| x.rename(names)).collect()),
+
+            Self::Function(captures, args, ret, body) => {
+                let captures = captures.iter().map(|capture| match capture {
+                    Capture::Value(x) => Capture::Value(name(x)),
+                    Capture::Reference(x) => Capture::Reference(name(x)),
+                }).collect();
+                let names = without(&args.iter().flat_map(|(pattern, _)| pattern.names()).collect::<Vec<_>>());
+                Self::Function(captures, args.clone(), ret.clone(), Box::new(body.rename(&names)))
+            }
+            Self::Generic(params, function) => Self::Generic(params.clone(), sub(function)),
+            Self::Let(x, t, val, body) => {
+                let names = without(&[x]);
+                Self::Let(x.clone(), t.clone(), Box::new(val.rename(&names)), Box::new(body.rename(&names)))
+            }
+            Self::LetInfer(x, val, body) => {
+                let names = without(&[x]);
+                Self::LetInfer(x.clone(), Box::new(val.rename(&names)), Box::new(body.rename(&names)))
+            }
+            Self::Destructure(pattern, t, val, body) => {
+                // The value is evaluated before the pattern's names are bound
+                let names = without(&pattern.names());
+                Self::Destructure(pattern.clone(), t.clone(), sub(val), Box::new(body.rename(&names)))
+            }
+            Self::Global(x, t, val, body) => {
+                let names = without(&[x]);
+                Self::Global(x.clone(), t.clone(), sub(val), Box::new(body.rename(&names)))
+            }
+            Self::Const(x, t, val, body) => {
+                let names = without(&[x]);
+                Self::Const(x.clone(), t.clone(), sub(val), Box::new(body.rename(&names)))
             }
 
-            Self::Nth(tup, n) => {
-                let tup_type = tup.get_type(scope)?;
-                let tup_size = tup_type.get_size()?;
-                if let Some((item_type, size_before)) = tup_type.nth(*n) {
-                    let item_size = item_type.get_size()?;
-                    Op::Do(vec![
-                        tup.compile(scope, offset)?,
-                        Op::PushLiteral(Literal(tup_size)),
-                        Op::Alloc,
-                        Op::Duplicate,
-                        Op::StoreAt(R1, 1),
-                        Op::Store(tup_size),
-
-                        Op::LoadFrom(R1, 1),
-                        Op::PushLiteral(Literal(size_before)),
-                        Op::Add,
-                        Op::Load(item_size),
-                        
-                        Op::LoadFrom(R1, 1),
-                        Op::Free,
-                    ])
-                } else {
-                    return Err(Error::NthOfNonTuple(self.clone(), tup_type))
-                }
+            Self::Deref(x) => Self::Deref(sub(x)),
+            Self::DerefAssign(a, b) => Self::DerefAssign(sub(a), sub(b)),
+            Self::Tuple(items) => Self::Tuple(items.iter().map(|x| x.rename(names)).collect()),
+            Self::Nth(x, n) => Self::Nth(sub(x), *n),
+            Self::ReferIndex(a, b) => Self::ReferIndex(sub(a), sub(b)),
+            Self::Index(a, b) => Self::Index(sub(a), sub(b)),
+            Self::IndexAssign(a, b, c) => Self::IndexAssign(sub(a), sub(b), sub(c)),
+
+            Self::Add(a, b) => Self::Add(sub(a), sub(b)),
+            Self::Sub(a, b) => Self::Sub(sub(a), sub(b)),
+            Self::Mul(a, b) => Self::Mul(sub(a), sub(b)),
+            Self::Div(a, b) => Self::Div(sub(a), sub(b)),
+            Self::Or(a, b) => Self::Or(sub(a), sub(b)),
+            Self::And(a, b) => Self::And(sub(a), sub(b)),
+            Self::Not(x) => Self::Not(sub(x)),
+            Self::Cast(x, t) => Self::Cast(sub(x), t.clone()),
+
+            Self::Putchar(x) => Self::Putchar(sub(x)),
+            Self::Putnum(x) => Self::Putnum(sub(x)),
+            Self::Free(x) => Self::Free(sub(x)),
+            Self::Realloc(ptr, n) => Self::Realloc(sub(ptr), sub(n)),
+            Self::SizeOf(t) => Self::SizeOf(t.clone()),
+            Self::Memcpy(dst, src, n) => Self::Memcpy(sub(dst), sub(src), sub(n)),
+            Self::Memset(dst, val, n) => Self::Memset(sub(dst), sub(val), sub(n)),
+            Self::Alloc(n, t, vals) => Self::Alloc(
+                sub(n),
+                t.clone(),
+                vals.as_ref().map(|vals| vals.iter().map(|x| x.rename(names)).collect())
+            ),
+            Self::Array(items) => Self::Array(items.iter().map(|x| x.rename(names)).collect()),
+
+            Self::Block(items) => Self::Block(items.iter().map(|x| x.rename(names)).collect()),
+            Self::While(a, b) => Self::While(sub(a), sub(b)),
+            Self::If(a, b) => Self::If(sub(a), sub(b)),
+            Self::Eq(a, b) => Self::Eq(sub(a), sub(b)),
+            Self::Neq(a, b) => Self::Neq(sub(a), sub(b)),
+        }
+    }
+
+    /// The variables and functions an expression uses that it doesn't define itself
+    pub fn free_names(&self) -> BTreeSet<String> {
+        let mut result = BTreeSet::new();
+        let all = |exprs: &[&Self], result: &mut BTreeSet<String>| {
+            for expr in exprs {
+                result.extend(expr.free_names());
+            }
+        };
+        let without = |expr: &Self, shadowed: &[&String]| {
+            let mut names = expr.free_names();
+            for name in shadowed {
+                names.remove(*name);
+            }
+            names
+        };
+        match self {
+            Self::Integer(_)
+            | Self::Signed(_)
+            | Self::Bool(_)
+            | Self::Character(_)
+            | Self::None
+            | Self::Getchar
+            | Self::Getnum
+            | Self::GetSigned => {}
+
+            Self::Variable(x)
+            | Self::Increment(x)
+            | Self::Decrement(x)
+            | Self::Refer(x) => { result.insert(x.clone()); }
+            Self::Assign(x, val) => {
+                result.insert(x.clone());
+                all(&[val], &mut result);
+            }
+            Self::Call(f, args) => {
+                result.insert(f.clone());
+                all(&args.iter().collect::<Vec<_>>(), &mut result);
             }
 
-            Self::Refer(name) => {
-                Op::Macro(name.clone())
+            Self::Function(captures, args, _, body) => {
+                result.extend(captures.iter().map(|capture| capture.name().clone()));
+                result.extend(without(body, &args.iter().flat_map(|(pattern, _)| pattern.names()).collect::<Vec<_>>()));
             }
-            Self::Deref(value) => {
-                let ptr_type = value.get_type(scope)?;
-                if let Type::Pointer(t) = ptr_type {
-                    Op::Do(vec![
-                        value.compile(scope, offset)?,
-                        Op::Load(t.get_size()?)
-                    ])
-                } else {
-                    return Err(Error::DerefNonPointer(self.clone(), ptr_type));
-                }
+            Self::Generic(_, function) => all(&[function], &mut result),
+            Self::Let(x, _, val, body) | Self::LetInfer(x, val, body) => {
+                result.extend(without(val, &[x]));
+                result.extend(without(body, &[x]));
             }
-            Self::DerefAssign(addr, value) => {
-                Op::Do(vec![
-                    value.compile(scope, offset)?,
-                    addr.compile(scope, offset)?,
-                    Op::Store(value.get_type(scope)?.get_size()?)
-                ])
+            Self::Destructure(pattern, _, val, body) => {
+                all(&[val], &mut result);
+                result.extend(without(body, &pattern.names()));
+            }
+            Self::Global(x, _, val, body) | Self::Const(x, _, val, body) => {
+                all(&[val], &mut result);
+                result.extend(without(body, &[x]));
             }
 
-            Self::Index(ptr, idx) => {
-                let ptr_type = ptr.get_type(scope)?;
-                if let Type::Pointer(t) = ptr_type {
-                    Self::Deref(
-                        Box::new(Self::Add(ptr.clone(), Box::new(Self::Mul(
-                            idx.clone(),
-                            Box::new(Self::Integer(t.get_size()?)),
-                        ))))
-                    ).compile(scope, offset)?
-                } else {
-                    return Err(Error::DerefNonPointer(self.clone(), ptr_type));
+            Self::Deref(x)
+            | Self::Nth(x, _)
+            | Self::Not(x)
+            | Self::Cast(x, _)
+            | Self::Putchar(x)
+            | Self::Putnum(x)
+            | Self::Free(x) => all(&[x], &mut result),
+            Self::SizeOf(_) => {}
+
+            Self::DerefAssign(a, b)
+            | Self::ReferIndex(a, b)
+            | Self::Index(a, b)
+            | Self::Add(a, b)
+            | Self::Sub(a, b)
+            | Self::Mul(a, b)
+            | Self::Div(a, b)
+            | Self::Or(a, b)
+            | Self::And(a, b)
+            | Self::While(a, b)
+            | Self::If(a, b)
+            | Self::Eq(a, b)
+            | Self::Neq(a, b)
+            | Self::Realloc(a, b) => all(&[a, b], &mut result),
+            Self::IndexAssign(a, b, c)
+            | Self::Memcpy(a, b, c)
+            | Self::Memset(a, b, c) => all(&[a, b, c], &mut result),
+
+            Self::Tuple(items) | Self::Block(items) | Self::Array(items) => all(&items.iter().collect::<Vec<_>>(), &mut result),
+            Self::Alloc(n, _, vals) => {
+                all(&[n], &mut result);
+                if let Some(vals) = vals {
+                    all(&vals.iter().collect::<Vec<_>>(), &mut result);
                 }
             }
+        }
+        result
+    }
 
-            Self::ReferIndex(ptr, idx) => {
-                let ptr_type = ptr.get_type(scope)?;
-                if let Type::Pointer(t) = ptr_type {
-                    Self::Add(ptr.clone(), Box::new(Self::Mul(
-                        Box::new(Self::Integer(t.get_size()?)),
-                        idx.clone(),
-                    ))).compile(scope, offset)?
-                } else {
-                    return Err(Error::DerefNonPointer(self.clone(), ptr_type));
-                }
+    /// Evaluate the parts of an expression that are known at compile time.
+    /// Constants with literal values are replaced with their values, arithmetic,
+    /// comparisons, and logic on literals are computed the way the target would
+    /// compute them, and branches that can never run are removed.
+    pub fn fold(&self, consts: &BTreeMap<String, Self>, target: &Target) -> Self {
+        let sub = |x: &Self| Box::new(x.fold(consts, target));
+        let without = |shadowed: &[&String]| {
+            let mut consts = consts.clone();
+            for name in shadowed {
+                consts.remove(*name);
+            }
+            consts
+        };
+        // Literals that fit in a cell on the target. Literals that don't
+        // are left alone, so they're still reported when they're compiled.
+        let int = |x: &Self| match x {
+            Self::Integer(n) if target.fits(*n) => Some(*n as i128),
+            _ => None,
+        };
+        let signed = |x: &Self| match x {
+            Self::Signed(n) if target.fits(n.unsigned_abs()) => Some(*n as i128),
+            _ => None,
+        };
+        let scalar = |x: &Self| match x {
+            Self::Bool(b) => Some(*b as u64),
+            Self::Character(ch) => Some(*ch as u8 as u64),
+            x => int(x).map(|n| n as u64),
+        };
+        let arithmetic = |a: &Self, b: &Self, op: fn(i128, i128) -> Option<i128>| {
+            if let (Some(a), Some(b)) = (int(a), int(b)) {
+                op(a, b).and_then(|n| overflow(n, target)).map(Self::Integer)
+            } else if let (Some(a), Some(b)) = (signed(a), signed(b)) {
+                op(a, b).filter(|n| target.fits(n.unsigned_abs() as u64)).map(|n| Self::Signed(n as i64))
+            } else {
+                None
             }
+        };
 
-            Self::IndexAssign(ptr, idx, val) => {
-                let ptr_type = ptr.get_type(scope)?;
-                if let Type::Pointer(t) = ptr_type {
-                    Self::DerefAssign(
-                        Box::new(Self::Add(ptr.clone(), Box::new(Self::Mul(
-                            Box::new(Self::Integer(t.get_size()?)),
-                            idx.clone(),
-                        )))),
-                        val.clone()
-                    ).compile(scope, offset)?
-                } else {
-                    return Err(Error::DerefNonPointer(self.clone(), ptr_type));
+        match self {
+            Self::Variable(x) if consts.contains_key(x) => consts[x].clone(),
+
+            Self::Integer(_)
+            | Self::Signed(_)
+            | Self::Bool(_)
+            | Self::Character(_)
+            | Self::None
+            | Self::Getchar
+            | Self::Getnum
+            | Self::GetSigned
+            | Self::Variable(_)
+            | Self::Increment(_)
+            | Self::Decrement(_)
+            | Self::Refer(_) => self.clone(),
+
+            Self::Assign(x, val) => Self::Assign(x.clone(), sub(val)),
+            Self::Call(f, args) => Self::Call(f.clone(), args.iter().map(|x| x.fold(consts, target)).collect()),
+
+            Self::Function(captures, args, ret, body) => {
+                let consts = without(&args.iter().flat_map(|(pattern, _)| pattern.names()).collect::<Vec<_>>());
+                Self::Function(captures.clone(), args.clone(), ret.clone(), Box::new(body.fold(&consts, target)))
+            }
+            Self::Generic(params, function) => Self::Generic(params.clone(), sub(function)),
+            Self::Let(x, t, val, body) => {
+                let consts = without(&[x]);
+                Self::Let(x.clone(), t.clone(), Box::new(val.fold(&consts, target)), Box::new(body.fold(&consts, target)))
+            }
+            Self::LetInfer(x, val, body) => {
+                let consts = without(&[x]);
+                Self::LetInfer(x.clone(), Box::new(val.fold(&consts, target)), Box::new(body.fold(&consts, target)))
+            }
+            Self::Destructure(pattern, t, val, body) => {
+                let consts = without(&pattern.names());
+                Self::Destructure(pattern.clone(), t.clone(), sub(val), Box::new(body.fold(&consts, target)))
+            }
+            Self::Global(x, t, val, body) => {
+                Self::Global(x.clone(), t.clone(), sub(val), Box::new(body.fold(&without(&[x]), target)))
+            }
+            Self::Const(x, t, val, body) => {
+                let val = val.fold(consts, target);
+                let mut consts = without(&[x]);
+                if scalar(&val).is_some() || signed(&val).is_some() {
+                    consts.insert(x.clone(), val.clone());
                 }
+                Self::Const(x.clone(), t.clone(), Box::new(val), Box::new(body.fold(&consts, target)))
             }
 
-            Self::Block(items) => {
-                let mut ops = vec![];
-                for (i, value) in items.iter().enumerate() {
-                    let size = value.get_type(scope)?.get_size()?;
-                    ops.push(value.compile(scope, offset)?);
-                    if size > 0 && i < items.len() - 1 {
-                        ops.push(Op::Stfree(size));
+            Self::Deref(x) => Self::Deref(sub(x)),
+            Self::DerefAssign(a, b) => Self::DerefAssign(sub(a), sub(b)),
+            Self::Tuple(items) => Self::Tuple(items.iter().map(|x| x.fold(consts, target)).collect()),
+            Self::Nth(x, n) => Self::Nth(sub(x), *n),
+            Self::ReferIndex(a, b) => Self::ReferIndex(sub(a), sub(b)),
+            Self::Index(a, b) => Self::Index(sub(a), sub(b)),
+            Self::IndexAssign(a, b, c) => Self::IndexAssign(sub(a), sub(b), sub(c)),
+
+            Self::Add(a, b) | Self::Sub(a, b) | Self::Mul(a, b) | Self::Div(a, b) => {
+                let (a, b) = (sub(a), sub(b));
+                let op: fn(i128, i128) -> Option<i128> = match self {
+                    Self::Add(_, _) => |a, b| Some(a + b),
+                    Self::Sub(_, _) => |a, b| Some(a - b),
+                    Self::Mul(_, _) => |a, b| Some(a * b),
+                    // Division by zero is left for the target to deal with
+                    _ => |a, b| if b == 0 { None } else { Some(a / b) },
+                };
+                match (arithmetic(&a, &b, op), self) {
+                    (Some(result), _) => result,
+                    (None, Self::Add(_, _)) => Self::Add(a, b),
+                    (None, Self::Sub(_, _)) => Self::Sub(a, b),
+                    (None, Self::Mul(_, _)) => Self::Mul(a, b),
+                    (None, _) => Self::Div(a, b),
+                }
+            }
+            Self::And(a, b) | Self::Or(a, b) => {
+                let (a, b) = (sub(a), sub(b));
+                match (&*a, &*b, self) {
+                    (Self::Bool(a), Self::Bool(b), Self::And(_, _)) => Self::Bool(*a && *b),
+                    (Self::Bool(a), Self::Bool(b), _) => Self::Bool(*a || *b),
+                    // The right side is only evaluated if the left side doesn't decide the result
+                    (Self::Bool(false), _, Self::And(_, _)) => Self::Bool(false),
+                    (Self::Bool(true), _, Self::Or(_, _)) => Self::Bool(true),
+                    (Self::Bool(_), _, _) => *b,
+                    (_, _, Self::And(_, _)) => Self::And(a, b),
+                    _ => Self::Or(a, b),
+                }
+            }
+            Self::Not(x) => match *sub(x) {
+                Self::Bool(b) => Self::Bool(!b),
+                x => Self::Not(Box::new(x)),
+            },
+            Self::Eq(a, b) | Self::Neq(a, b) => {
+                let (a, b) = (sub(a), sub(b));
+                let equal = match (scalar(&a), scalar(&b)) {
+                    _ if core::mem::discriminant(&*a) != core::mem::discriminant(&*b) => None,
+                    (Some(x), Some(y)) => Some(x == y),
+                    _ => signed(&a).zip(signed(&b)).map(|(x, y)| x == y),
+                };
+                match (equal, self) {
+                    (Some(equal), Self::Eq(_, _)) => Self::Bool(equal),
+                    (Some(equal), _) => Self::Bool(!equal),
+                    (None, Self::Eq(_, _)) => Self::Eq(a, b),
+                    (None, _) => Self::Neq(a, b),
+                }
+            }
+            Self::Cast(x, t) => {
+                let x = sub(x);
+                match (&*x, t) {
+                    (_, Type::Bool) if scalar(&x).is_some() => Self::Bool(scalar(&x) != Some(0)),
+                    (_, Type::Integer) if scalar(&x).is_some() => Self::Integer(scalar(&x).unwrap_or(0)),
+                    (_, Type::Character) if scalar(&x).is_some_and(|n| n <= u8::MAX as u64) => {
+                        Self::Character(scalar(&x).unwrap_or(0) as u8 as char)
                     }
+                    (Self::Integer(n), Type::Signed) if int(&x).is_some() => Self::Signed(*n as i64),
+                    _ => Self::Cast(x, t.clone()),
                 }
-                Op::Do(ops)
             }
 
-            Self::Assign(name, expr) => {
-                Op::Do(vec![
-                    expr.compile(scope, offset)?,
-                    Op::Macro(name.clone()),
-                    Op::Store(scope.get(name).ok_or(Error::VariableNotInScope(name.clone()))?.get_size()?)
-                ])
-            }
+            Self::Putchar(x) => Self::Putchar(sub(x)),
+            Self::Putnum(x) => Self::Putnum(sub(x)),
+            Self::Free(x) => Self::Free(sub(x)),
+            Self::Realloc(ptr, n) => Self::Realloc(sub(ptr), sub(n)),
+            Self::SizeOf(t) => Self::SizeOf(t.clone()),
+            Self::Memcpy(dst, src, n) => Self::Memcpy(sub(dst), sub(src), sub(n)),
+            Self::Memset(dst, val, n) => Self::Memset(sub(dst), sub(val), sub(n)),
+            Self::Alloc(n, t, vals) => Self::Alloc(
+                sub(n),
+                t.clone(),
+                vals.as_ref().map(|vals| vals.iter().map(|x| x.fold(consts, target)).collect())
+            ),
+            Self::Array(items) => Self::Array(items.iter().map(|x| x.fold(consts, target)).collect()),
 
-            Self::If(item, expr) => {
-                Op::If(vec![item.compile(scope, offset)?], vec![expr.compile(scope, offset)?])
-            }
-
-            Self::While(item, expr) => {
-                Op::While(vec![item.compile(scope, offset)?], vec![expr.compile(scope, offset)?])
+            Self::Block(items) => {
+                let mut items = items.iter().map(|x| x.fold(consts, target)).collect::<Vec<_>>();
+                // Literals that aren't the result of the block don't do anything
+                let last = items.pop();
+                items.retain(|x| !matches!(x, Self::None | Self::Integer(_) | Self::Bool(_) | Self::Character(_)));
+                items.extend(last);
+                Self::Block(items)
             }
-
-            Self::None => Op::Do(vec![]),
-            Self::Integer(i) => Op::PushLiteral(Literal(*i)),
-            Self::Bool(b) => Op::PushLiteral(Literal(*b as u32)),
-            Self::Character(ch) => Op::PushLiteral(Literal(*ch as u8 as u32)),
-
-            Self::Putchar(x) => Op::Do(vec![
-                x.compile(scope, offset)?,
-                Op::Putchar
-            ]),
-            Self::Putnum(x) => Op::Do(vec![
-                x.compile(scope, offset)?,
-                Op::Putnum
-            ]),
-            Self::Alloc(x, t, vals) => Op::Do(vec![
-                x.compile(scope, offset)?,
-                Op::PushLiteral(Literal(t.get_size()?)),
-                Op::Mul,
-                // Op::Increment(SP.deref()),
-                // Op::Duplicate,
-                // Op::Putnum,
-                Op::Alloc,
-                Op::Do(if let Some(vals) = vals {
-                    let mut result = vec![Op::StoreAt(R3, 1)];
-                    let mut size = 0;
-                    for val in vals {
-                        size += val.get_type(scope)?.get_size()?;
-                        result.push(val.compile(scope, offset)?)
-                    }
-                    result.extend([
-                        Op::LoadFrom(R3, 1),
-                        Op::Store(size),
-                        Op::LoadFrom(R3, 1),
-                    ]);
-
-                    result
-                } else {
-                    vec![]
-                })
-            ]),
-            Self::Free(x) => Op::Do(vec![
-                x.compile(scope, offset)?,
-                Op::Free
-            ]),
-            Self::Getchar => Op::Getchar,
-            Self::Getnum => Op::Getnum,
-
-            Self::Function(args, ret, body) => {
-                let mut scope = scope.clone();
-
-                for (name, t) in args {
-                    scope.insert(name.clone(), t.clone());
-                }
-                function(
-                    args.iter().map(|(name, t)| Ok((name.clone(), t.get_size()?)))
-                        .collect::<Result<Vec<_>, Error>>()?,
-                    ret.get_size()?,
-                    vec![body.compile(&scope, offset)?]
-                )
+            Self::While(cond, body) => match *sub(cond) {
+                Self::Bool(false) => Self::None,
+                cond => Self::While(Box::new(cond), sub(body)),
             },
+            Self::If(cond, body) => match *sub(cond) {
+                Self::Bool(false) => Self::None,
+                Self::Bool(true) => *sub(body),
+                cond => Self::If(Box::new(cond), sub(body)),
+            },
+        }
+    }
 
-            Self::Add(a, b) => {
-                Op::Do(vec![
-                    a.compile(scope, offset)?,
-                    b.compile(scope, offset)?,
-                    Op::Add
-                ])
-            }
-
-            Self::Sub(a, b) => {
-                Op::Do(vec![
-                    a.compile(scope, offset)?,
-                    b.compile(scope, offset)?,
-                    Op::Sub
-                ])
-            }
-
-            Self::Mul(a, b) => {
-                Op::Do(vec![
-                    a.compile(scope, offset)?,
-                    b.compile(scope, offset)?,
-                    Op::Mul
-                ])
-            }
-
-            Self::Div(a, b) => {
-                Op::Do(vec![
-                    a.compile(scope, offset)?,
-                    b.compile(scope, offset)?,
-                    Op::Div
-                ])
-            }
-
-            Self::And(a, b) => {
-                Op::Do(vec![
-                    a.compile(scope, offset)?,
-                    b.compile(scope, offset)?,
-                    Op::And
-                ])
-            }
+    /// Specialize a call to a generic function for the types of its arguments.
+    /// Returns the name, type, and definition of the specialized function.
+    fn specialize(&self, scope: &BTreeMap<String, Type>, arg_types: &[Type]) -> Result<(String, Type, Self), Error> {
+        if let Self::Call(name, args) = self {
+            if let Some(t @ Type::Generic(params, function)) = scope.get(name) {
+                if let Self::Function(_, params_types, _, _) = &**function {
+                    if params_types.len() != args.len() {
+                        return Err(Error::MismatchedTypes(Box::new(self.clone()), t.clone(), Type::Void));
+                    }
 
-            Self::Or(a, b) => {
-                Op::Do(vec![
-                    a.compile(scope, offset)?,
-                    b.compile(scope, offset)?,
-                    Op::Or
-                ])
-            }
+                    let mut types = BTreeMap::new();
+                    for ((_, param_type), arg_type) in params_types.iter().zip(arg_types) {
+                        if !param_type.unify(arg_type, &mut types) {
+                            return Err(Error::MismatchedTypes(Box::new(self.clone()), param_type.substitute(&types), arg_type.clone()));
+                        }
+                    }
 
-            Self::Not(x) => {
-                Op::Do(vec![
-                    x.compile(scope, offset)?,
-                    Op::Not
-                ])
-            }
+                    let mut specialized_name = format!("{}<", name);
+                    for param in params {
+                        let t = types.get(param).ok_or_else(|| Error::CannotInfer(self.clone(), param.clone()))?;
+                        specialized_name += &format!("{}, ", t);
+                    }
+                    specialized_name += ">";
 
-            Self::Eq(a, b) => {
-                if **b == Self::Integer(0) {
-                    Op::Do(vec![
-                        a.compile(scope, offset)?,
-                        Op::Not
-                    ])
-                } else if **a == Self::Integer(0) {
-                    Op::Do(vec![
-                        b.compile(scope, offset)?,
-                        Op::Not
-                    ])
-                } else {
-                    Op::Do(vec![
-                        b.compile(scope, offset)?,
-                        a.compile(scope, offset)?,
-                        Op::Eq
-                    ])
+                    if let Self::Function(captures, args, ret, body) = function.substitute(&types) {
+                        let t = Type::Function(args.iter().map(|(_, t)| t.clone()).collect(), Box::new(ret.clone()));
+                        return Ok((specialized_name, t, Self::Function(captures, args, ret, body)));
+                    }
                 }
             }
+        }
+        Err(Error::CallNonFunction(self.to_string()))
+    }
 
-            Self::Neq(a, b) => {
-                if **b == Self::Integer(0) {
-                    a.compile(scope, offset)?
-                } else if **a == Self::Integer(0) {
-                    b.compile(scope, offset)?
-                } else if **b == Self::Character('\0') {
-                    a.compile(scope, offset)?
-                } else if **a == Self::Character('\0') {
-                    b.compile(scope, offset)?
-                } else {
-                    Op::Do(vec![
-                        b.compile(scope, offset)?,
-                        a.compile(scope, offset)?,
-                        Op::Neq
-                    ])
+    /// The scope that the body of a function is type checked in.
+    /// Functions can only use their arguments, their captured variables, and
+    /// functions that don't capture anything.
+    fn body_scope(&self, scope: &BTreeMap<String, Type>) -> Result<BTreeMap<String, Type>, Error> {
+        let mut result = BTreeMap::new();
+        if let Self::Function(captures, args, _, _) = self {
+            for (name, t) in scope {
+                if matches!(t, Type::Function(_, _)) && scope.contains_key(&index_name(name)) {
+                    result.insert(name.clone(), t.clone());
+                    result.insert(index_name(name), Type::Integer);
+                } else if matches!(t, Type::Generic(_, _)) {
+                    result.insert(name.clone(), t.clone());
+                } else if name.ends_with(".global") || name.ends_with(".const") {
+                    // Globals can be used in every function
+                    let global = &name[..name.rfind('.').unwrap()];
+                    result.insert(name.clone(), t.clone());
+                    result.insert(global.to_string(), scope[global].clone());
                 }
             }
-
-            Self::Variable(name) => {
-                Op::Do(vec![
-                    Op::Macro(name.clone()),
-                    Op::Load(scope.get(name).ok_or(Error::VariableNotInScope(name.clone()))?.get_size()?)
-                ])
-
+            for capture in captures {
+                let name = capture.name();
+                let t = scope.get(name).ok_or_else(|| Error::VariableNotInScope(name.clone()))?;
+                result = bind(&result, name, t, &Expr::None);
             }
-
-            Self::Let(name, t, expr, body) => {
-                let mut scope = scope.clone();
-                scope.insert(name.clone(), t.clone());
-                if let Type::Function(args, _) = t {
-                    let mut args_size = 0;
-                    for arg in args {
-                        args_size += arg.get_size()?;
-                    }
-                    *offset += args_size;
-                    let expr_result = expr.compile(&scope, offset)?;
-                    *offset -= args_size;
-
-                    let result = Op::Let(name.clone(), vec![
-                        expr_result
-                    ], vec![
-                        body.compile(&scope, offset)?,
-                    ]);
-                    result
-                } else {
-                    let this_offset = *offset;
-                    let size = t.get_size()?;
-                    *offset += size;
-                    let expr_result = expr.compile(&scope, offset)?;
-                    let body_result = body.compile(&scope, offset)?;
-                    *offset -= size;
-    
-                    let result_type = body.get_type(&scope)?;
-                    let result_size = result_type.get_size()?;
-
-                    let result = Op::Let(name.clone(), vec![
-                        Op::LoadFrom(FP, 1),
-                        Op::PushLiteral(Literal(this_offset)),
-                        Op::Add
-                    ], vec![
-                        // Allocate space on the stack to
-                        // store the value
-                        Op::Stalloc(size),
-                        // Store the value
-                        expr_result,
-                        Op::Macro(name.clone()),
-                        Op::Store(size),
-
-                        body_result,
-
-                        Op::Do(if result_size > 0 {
-                            vec![
-                                Op::PushLiteral(Literal(result_size)),
-                                Op::Alloc,
-                                Op::Duplicate,
-                                Op::StoreAt(R2, 1),
-                                Op::Store(result_size),
-                                Op::Stfree(size),
-        
-                                Op::LoadFrom(R2, 1),
-                                Op::Load(result_size),
-                                Op::LoadFrom(R2, 1),
-                                Op::Free,
-                            ]
-                        } else {
-                            vec![
-                                Op::Stfree(size),
-                            ]
-                        })
-                    ]);
-                    result
+            for (pattern, t) in args {
+                for (name, t) in pattern.bindings(t)? {
+                    result = bind(&result, &name, &t, &Expr::None);
                 }
             }
+        }
+        Ok(result)
+    }
 
-            Self::LetInfer(name, expr, body) => {
-                Self::Let(name.clone(), expr.get_type(scope)?, expr.clone(), body.clone()).compile(scope, offset)?
-            }
+    /// Type check the value and body of a `let`, in a scope where the
+    /// variable is already bound
+    fn check_let(&self, name: &str, val: &Self, typed_val: Typed, body: &Self, scope: &BTreeMap<String, Type>, generics: &mut Vec<Instances>) -> Result<Typed, Error> {
+        if let Self::Generic(_, _) = val {
+            // Instances are checked where they're first called, and defined once here
+            generics.push(Instances { name: name.to_string(), scope: scope.clone(), functions: vec![] });
+            let body = body.check_with(scope, generics)?;
+            let instances = generics.pop().map(|generic| generic.functions).unwrap_or_default();
+            let instances = instances.into_iter().filter_map(|(name, function)| Some((name, function?))).collect();
+            let t = body.t.clone();
+            return Typed::new(Node::LetGeneric(instances, Box::new(body)), t);
+        }
 
-            Self::Call(name, args) => {
-                let mut result = vec![];
-                for arg in args {
-                    result.push(arg.compile(scope, offset)?);
+        let body = body.check_with(scope, generics)?;
+        let t = body.t.clone();
+        let node = match val {
+            Self::Function(captures, _, _, _) if captures.is_empty() => {
+                // A function can only be called through its index where it's defined
+                if t.contains(&typed_val.t) {
+                    return Err(Error::FunctionEscapes(name.to_string()));
                 }
-                result.push(Op::Macro(name.clone()));
-                Op::Do(result)
+                Node::LetFunction(name.to_string(), Box::new(typed_val), Box::new(body))
             }
-        })
+            Self::Function(_, _, _, _) => {
+                let env = env_name(name);
+                let env_size = scope.get(&env).ok_or(Error::VariableNotInScope(env.clone()))?.get_size()?;
+                Node::LetClosure(name.to_string(), env_size, Box::new(typed_val), Box::new(body))
+            }
+            _ => Node::Let(name.to_string(), Box::new(typed_val), Box::new(body)),
+        };
+        Typed::new(node, t)
     }
 
-    fn type_check(&self, scope: &BTreeMap<String, Type>) -> Result<(), Error> {
-        self.get_type(scope)?;
-        Ok(())
+    /// Type check an expression, and annotate it and everything in it
+    /// with their types. Each expression is only checked once.
+    pub fn check(&self, scope: &BTreeMap<String, Type>) -> Result<Typed, Error> {
+        self.check_with(scope, &mut vec![])
     }
 
-    fn get_type(&self, scope: &BTreeMap<String, Type>) -> Result<Type, Error> {
-        Ok(match self {
+    /// Type check an expression, with the instances of the generic functions in scope
+    fn check_with(&self, scope: &BTreeMap<String, Type>, generics: &mut Vec<Instances>) -> Result<Typed, Error> {
+        let (node, t) = match self {
             Self::Alloc(n, t, vals) => {
-                let count_type = n.get_type(scope)?;
-                if count_type != Type::Integer {
-                    return Err(Error::MismatchedTypes(self.clone(), Type::Integer, count_type));
+                let n = n.check_with(scope, generics)?;
+                if n.t != Type::Integer {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Integer, n.t));
                 }
                 if t == &Type::Void {
                     return Err(Error::AllocVoid(self.clone()));
                 }
-                if let Some(vals) = vals {
+                let vals = if let Some(vals) = vals {
+                    let mut result = vec![];
                     for val in vals {
-                        let val_type = val.get_type(scope)?;
-                        if &val_type != t {
-                            return Err(Error::MismatchedTypes(self.clone(), t.clone(), val_type));
+                        let val = val.check_with(scope, generics)?;
+                        if &val.t != t {
+                            return Err(Error::MismatchedTypes(Box::new(self.clone()), t.clone(), val.t));
                         }
+                        result.push(val);
                     }
+                    Some(result)
+                } else {
+                    None
+                };
+                (Node::Alloc(Box::new(n), t.get_size()?, vals), Type::Pointer(Box::new(t.clone())))
+            }
+            Self::Array(items) => {
+                let mut typed_items: Vec<(Expr, Typed)> = vec![];
+                for item in items {
+                    let typed = item.check_with(scope, generics)?;
+                    if !typed.is_constant() {
+                        return Err(Error::NonConstantArray(item.clone()));
+                    }
+                    if let Some((_, first)) = typed_items.first() {
+                        if first.t != typed.t {
+                            return Err(Error::MismatchedTypes(Box::new(self.clone()), first.t.clone(), typed.t));
+                        }
+                    }
+                    typed_items.push((item.clone(), typed));
                 }
-                Type::Pointer(Box::new(t.clone()))
+                let t = match typed_items.first() {
+                    Some((_, first)) => first.t.clone(),
+                    None => return Err(Error::EmptyArray(self.clone())),
+                };
+                if t == Type::Void {
+                    return Err(Error::AllocVoid(self.clone()));
+                }
+                (Node::Array(typed_items), Type::Pointer(Box::new(t)))
             }
 
             Self::Increment(name) | Self::Decrement(name) => {
                 let var_type = scope.get(name).ok_or(Error::VariableNotInScope(name.clone()))?;
-                
+                if scope.contains_key(&const_name(name)) {
+                    return Err(Error::ConstNotVariable(name.clone()));
+                }
                 if *var_type != Type::Integer {
-                    return Err(Error::MismatchedTypes(self.clone(), Type::Integer, var_type.clone()));
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Integer, var_type.clone()));
                 }
-                Type::Void
+                (if let Self::Increment(_) = self {
+                    Node::Increment(address_name(scope, name))
+                } else {
+                    Node::Decrement(address_name(scope, name))
+                }, Type::Void)
             }
 
             Self::Tuple(items) => {
+                let mut typed_items = vec![];
                 let mut item_types = vec![];
                 for item in items {
-                    item_types.push(item.get_type(scope)?)
+                    let item = item.check_with(scope, generics)?;
+                    item_types.push(item.t.clone());
+                    typed_items.push(item);
                 }
-                Type::Tuple(item_types)
+                (Node::Tuple(typed_items), Type::Tuple(item_types))
             }
 
             Self::Nth(tup, n) => {
-                let tup_type = tup.get_type(scope)?;
-                if let Some((t, _)) = tup_type.nth(*n) {
-                    t.clone()
+                let tup = tup.check_with(scope, generics)?;
+                if let Some((t, size_before)) = tup.t.nth(*n) {
+                    let t = t.clone();
+                    (Node::Nth(Box::new(tup), size_before), t)
                 } else {
-                    return Err(Error::NthOfNonTuple(self.clone(), tup_type))
+                    return Err(Error::NthOfNonTuple(Box::new(self.clone()), tup.t))
                 }
             }
 
-            Self::Free(x) => {
-                let x_type = x.get_type(scope)?;
-                if let Type::Pointer(t) = x_type {
-                    t.get_size()?;
-                    Type::Void
+            Self::Realloc(ptr, n) => {
+                let ptr = ptr.check_with(scope, generics)?;
+                let n = n.check_with(scope, generics)?;
+                if n.t != Type::Integer {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Integer, n.t));
+                }
+                if let Type::Pointer(t) = &ptr.t {
+                    let size = t.get_size()?;
+                    let t = ptr.t.clone();
+                    (Node::Realloc(Box::new(ptr), Box::new(n), size), t)
                 } else {
-                    return Err(Error::MismatchedTypes(self.clone(), Type::Pointer(Box::new(Type::Void)), x_type));
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Pointer(Box::new(Type::Void)), ptr.t));
                 }
             }
-
-            Self::Index(ptr, idx) => {
-                let ptr_type = ptr.get_type(scope)?;
-                let idx_type = idx.get_type(scope)?;
-                if let Type::Pointer(val) = ptr_type {
-                    if idx_type != Type::Integer {
-                        return Err(Error::MismatchedTypes(self.clone(), Type::Integer, idx_type))
-                    }
-                    *val
-                } else {
-                    return Err(Error::DerefNonPointer(self.clone(), ptr_type))   
+            Self::SizeOf(t) => (Node::Integer(t.get_size()? as u64), Type::Integer),
+            Self::Memcpy(dst, src, n) | Self::Memset(dst, src, n) => {
+                let dst = dst.check_with(scope, generics)?;
+                let src = src.check_with(scope, generics)?;
+                let n = n.check_with(scope, generics)?;
+                if n.t != Type::Integer {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Integer, n.t));
                 }
+                let t = match &dst.t {
+                    Type::Pointer(t) => t,
+                    _ => return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Pointer(Box::new(Type::Void)), dst.t)),
+                };
+                // `memcpy` copies from another pointer, and `memset` stores a value
+                let src_type = if let Self::Memcpy(..) = self { dst.t.clone() } else { *t.clone() };
+                if src.t != src_type {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), src_type, src.t));
+                }
+                let size = t.get_size()?;
+                (if let Self::Memcpy(..) = self {
+                    Node::Memcpy(Box::new(dst), Box::new(src), Box::new(n), size)
+                } else {
+                    Node::Memset(Box::new(dst), Box::new(src), Box::new(n))
+                }, Type::Void)
             }
-
-            Self::ReferIndex(ptr, idx) => {
-                let ptr_type = ptr.get_type(scope)?;
-                let idx_type = idx.get_type(scope)?;
-                if let Type::Pointer(_) = ptr_type {
-                    if idx_type != Type::Integer {
-                        return Err(Error::MismatchedTypes(self.clone(), Type::Integer, idx_type))
-                    }
-                    ptr_type
+            Self::Free(x) => {
+                let x = x.check_with(scope, generics)?;
+                if let Type::Pointer(t) = &x.t {
+                    t.get_size()?;
+                    (Node::Free(Box::new(x)), Type::Void)
                 } else {
-                    return Err(Error::DerefNonPointer(self.clone(), ptr_type))   
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Pointer(Box::new(Type::Void)), x.t));
                 }
             }
 
-            Self::IndexAssign(ptr, idx, val) => {
-                let ptr_type = ptr.get_type(scope)?;
-                let idx_type = idx.get_type(scope)?;
-                let val_type = val.get_type(scope)?;
-                if let Type::Pointer(ptr_val_type) = ptr_type {
-                    if idx_type != Type::Integer {
-                        return Err(Error::MismatchedTypes(self.clone(), Type::Integer, idx_type))
+            // Indexing is pointer arithmetic: `ptr[idx]` is `*(ptr + idx * size)`
+            Self::Index(ptr, idx) | Self::ReferIndex(ptr, idx) | Self::IndexAssign(ptr, idx, _) => {
+                let ptr = ptr.check_with(scope, generics)?;
+                let idx = idx.check_with(scope, generics)?;
+                let val_type = if let Type::Pointer(val_type) = &ptr.t {
+                    *val_type.clone()
+                } else {
+                    return Err(Error::DerefNonPointer(Box::new(self.clone()), ptr.t))
+                };
+                if idx.t != Type::Integer {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Integer, idx.t))
+                }
+                let size = Typed::new(Node::Integer(val_type.get_size()? as u64), Type::Integer)?;
+                let ptr_type = ptr.t.clone();
+                match self {
+                    Self::Index(_, _) => {
+                        let offset = Typed::new(Node::Mul(Box::new(idx), Box::new(size)), Type::Integer)?;
+                        let address = Typed::new(Node::Add(Box::new(ptr), Box::new(offset)), ptr_type)?;
+                        (Node::Deref(Box::new(address)), val_type)
                     }
-                    if val_type != *ptr_val_type {
-                        return Err(Error::MismatchedTypes(self.clone(), *ptr_val_type, val_type))
+                    Self::ReferIndex(_, _) => {
+                        let offset = Typed::new(Node::Mul(Box::new(size), Box::new(idx)), Type::Integer)?;
+                        (Node::Add(Box::new(ptr), Box::new(offset)), ptr_type)
                     }
-                    Type::Void
-                } else {
-                    return Err(Error::DerefNonPointer(self.clone(), ptr_type))   
+                    Self::IndexAssign(_, _, val) => {
+                        let val = val.check_with(scope, generics)?;
+                        if val.t != val_type {
+                            return Err(Error::MismatchedTypes(Box::new(self.clone()), val_type, val.t))
+                        }
+                        let offset = Typed::new(Node::Mul(Box::new(size), Box::new(idx)), Type::Integer)?;
+                        let address = Typed::new(Node::Add(Box::new(ptr), Box::new(offset)), ptr_type)?;
+                        (Node::DerefAssign(Box::new(address), Box::new(val)), Type::Void)
+                    }
+                    _ => unreachable!()
                 }
             }
 
             Self::Refer(name) => {
                 let var_type = scope.get(name).ok_or(Error::VariableNotInScope(name.clone()))?;
+                if scope.contains_key(&index_name(name)) || scope.contains_key(&env_name(name)) {
+                    return Err(Error::FunctionNotVariable(name.clone()));
+                } else if scope.contains_key(&const_name(name)) {
+                    return Err(Error::ConstNotVariable(name.clone()));
+                }
                 var_type.get_size()?;
 
-                Type::Pointer(Box::new(var_type.clone()))
+                (Node::Refer(address_name(scope, name)), Type::Pointer(Box::new(var_type.clone())))
             }
             Self::Deref(value) => {
-                let ptr_type = value.get_type(scope)?;
-                if let Type::Pointer(t) = ptr_type {
-                    t.get_size()?;
-                    *t.clone()
+                let value = value.check_with(scope, generics)?;
+                if let Type::Pointer(t) = &value.t {
+                    let t = *t.clone();
+                    (Node::Deref(Box::new(value)), t)
                 } else {
-                    return Err(Error::DerefNonPointer(self.clone(), ptr_type));
+                    return Err(Error::DerefNonPointer(Box::new(self.clone()), value.t));
                 }
             }
-            Self::DerefAssign(addr, value) => {
-                Self::Deref(addr.clone()).get_type(scope)?;
-                let value_type = value.get_type(scope)?;
-                let ptr_type = addr.get_type(scope)?;
-                if let Type::Pointer(t) = ptr_type {
+            Self::DerefAssign(addr_expr, value) => {
+                let addr = addr_expr.check_with(scope, generics)?;
+                let t = if let Type::Pointer(t) = &addr.t {
                     t.get_size()?;
-                    if *t.clone() != value_type {
-                        return Err(Error::MismatchedTypes(self.clone(), *t.clone(), value_type));
-                    }
-                    Type::Void
+                    *t.clone()
                 } else {
-                    return Err(Error::DerefNonPointer(self.clone(), ptr_type));
+                    return Err(Error::DerefNonPointer(Box::new(Self::Deref(addr_expr.clone())), addr.t));
+                };
+                let value = value.check_with(scope, generics)?;
+                if t != value.t {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), t, value.t));
                 }
+                (Node::DerefAssign(Box::new(addr), Box::new(value)), Type::Void)
             }
 
             Self::Block(items) => {
-                let mut result = Type::Void; 
+                let mut result = Type::Void;
+                let mut typed_items = vec![];
                 for item in items {
-                    result = item.get_type(scope)?
+                    let item = item.check_with(scope, generics)?;
+                    result = item.t.clone();
+                    typed_items.push(item);
                 }
-                result
+                (Node::Block(typed_items), result)
             }
 
-            Self::If(cond, body) => {
-                let cond_type = cond.get_type(scope)?;
-                let body_type = body.get_type(scope)?;
-                if cond_type != Type::Bool {
-                    return Err(Error::MismatchedTypes(self.clone(), Type::Bool, cond_type))
-                } else if body_type != Type::Void {
-                    return Err(Error::MismatchedTypes(self.clone(), Type::Void, body_type))
-                } else {
-                    Type::Void
+            Self::If(cond, body) | Self::While(cond, body) => {
+                let cond = cond.check_with(scope, generics)?;
+                let body = body.check_with(scope, generics)?;
+                if cond.t != Type::Bool {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Bool, cond.t))
+                } else if body.t != Type::Void {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Void, body.t))
                 }
-            }
-
-            Self::While(cond, body) => {
-                let cond_type = cond.get_type(scope)?;
-                let body_type = body.get_type(scope)?;
-                if cond_type != Type::Bool {
-                    return Err(Error::MismatchedTypes(self.clone(), Type::Bool, cond_type))
-                } else if body_type != Type::Void {
-                    return Err(Error::MismatchedTypes(self.clone(), Type::Void, body_type))
+                (if let Self::If(_, _) = self {
+                    Node::If(Box::new(cond), Box::new(body))
                 } else {
-                    Type::Void
-                }
+                    Node::While(Box::new(cond), Box::new(body))
+                }, Type::Void)
             }
 
-            Self::Integer(_) => Type::Integer,
-            Self::None => Type::Void,
-            Self::Bool(_) => Type::Bool,
-            Self::Character(_) => Type::Character,
-            
-            Self::Function(args, ret, expr) => {
-                let mut test_scope = BTreeMap::new();
-                // Don't include variables from outer scopes
-                for (name, t) in scope {
-                    if matches!(t, Type::Function(_, _)) {
-                        test_scope.insert(name.clone(), t.clone());
+            Self::Integer(i) => (Node::Integer(*i), Type::Integer),
+            Self::Generic(params, function) => (Node::Generic, Type::Generic(params.clone(), function.clone())),
+            Self::Signed(i) => (Node::Signed(*i), Type::Signed),
+            Self::None => (Node::None, Type::Void),
+            Self::Bool(b) => (Node::Bool(*b), Type::Bool),
+            Self::Character(ch) => (Node::Character(*ch), Type::Character),
+
+            Self::Function(captures, args, ret, expr) => {
+                unique_names(args.iter().flat_map(|(pattern, _)| pattern.names()))?;
+                let expr = match expr.check_with(&self.body_scope(scope)?, generics) {
+                    Err(Error::VariableNotInScope(name)) if scope.contains_key(&env_name(&name)) => {
+                        return Err(Error::ClosureInFunction(name))
                     }
-                }
-
-                for (name, t) in args {
-                    test_scope.insert(name.clone(), t.clone());
-                }
-                
-                let expr_type = expr.get_type(&test_scope)?;
-                if expr_type != *ret {
+                    Err(Error::VariableNotInScope(name)) if scope.contains_key(&name) => {
+                        return Err(Error::VariableNotCaptured(name))
+                    }
+                    result => result?
+                };
+                if expr.t != *ret {
                     return Err(Error::MismatchedTypes(
-                        self.clone(),
+                        Box::new(self.clone()),
                         ret.clone(),
-                        expr_type,
+                        expr.t,
                     ));
                 }
 
-                Type::Function(
+                // The members of a tuple argument are stored one after another,
+                // so the names in its pattern are bound like separate arguments
+                let mut arg_sizes = vec![];
+                for (pattern, t) in args {
+                    for (name, t) in pattern.bindings(t)? {
+                        arg_sizes.push((name, t.get_size()?));
+                    }
+                }
+                let mut env = vec![];
+                for capture in captures {
+                    let t = scope.get(capture.name()).ok_or_else(|| Error::VariableNotInScope(capture.name().clone()))?;
+                    env.push(match capture {
+                        Capture::Value(name) => mir::Capture::Value(name.clone(), t.get_size()?),
+                        Capture::Reference(name) => mir::Capture::Reference(name.clone()),
+                    });
+                }
+
+                (Node::Function(arg_sizes, env, Box::new(expr)), Type::Function(
                     args.iter().map(|(_, t)| t.clone()).collect::<Vec<_>>(),
                     Box::new(ret.clone())
-                )
+                ))
             },
             Self::Variable(name) => {
-                if let Some(t) = scope.get(name) {
-                    t.clone()
+                if scope.contains_key(&env_name(name)) {
+                    return Err(Error::ClosureAsValue(name.clone()));
+                } else if let Some(Type::Generic(_, _)) = scope.get(name) {
+                    return Err(Error::GenericAsValue(name.clone()));
+                } else if let Some(t) = scope.get(name) {
+                    if scope.contains_key(&index_name(name)) {
+                        (Node::FunctionIndex(index_name(name)), t.clone())
+                    } else {
+                        (Node::Variable(address_name(scope, name)), t.clone())
+                    }
                 } else {
                     return Err(Error::VariableNotInScope(name.clone()));
                 }
@@ -940,121 +1769,870 @@ impl Expr {
                     match t {
                         Type::Function(params, ret) => {
                             if params.len() != args.len() {
-                                return Err(Error::MismatchedTypes(self.clone(), t.clone(), Type::Void));
+                                return Err(Error::MismatchedTypes(Box::new(self.clone()), t.clone(), Type::Void));
                             }
+                            let mut typed_args = vec![];
                             for (a, b) in params.iter().zip(args.iter()) {
-                                let b_type = b.get_type(scope)?;
-                                if a != &b_type {
-                                    return Err(Error::MismatchedTypes(self.clone(), a.clone(), b_type));
+                                let b = b.check_with(scope, generics)?;
+                                if a != &b.t {
+                                    return Err(Error::MismatchedTypes(Box::new(self.clone()), a.clone(), b.t));
                                 }
+                                typed_args.push(b);
                             }
 
-                            *ret.clone()
+                            (if scope.contains_key(&index_name(name)) || scope.contains_key(&env_name(name)) {
+                                Node::Call(name.clone(), typed_args)
+                            } else {
+                                // The function is a value, so call it by its index
+                                let function = Typed::new(Node::Variable(address_name(scope, name)), t.clone())?;
+                                Node::CallIndirect(Box::new(function), typed_args)
+                            }, *ret.clone())
                         },
+                        Type::Generic(_, _) => {
+                            let mut typed_args = vec![];
+                            for arg in args {
+                                typed_args.push(arg.check_with(scope, generics)?);
+                            }
+                            let arg_types = typed_args.iter().map(|arg| arg.t.clone()).collect::<Vec<_>>();
+                            let index = generics.iter().rposition(|generic| &generic.name == name)
+                                .ok_or_else(|| Error::VariableNotInScope(name.clone()))?;
+                            if generics[index].functions.iter().any(|(_, function)| function.is_none()) {
+                                // The call is in the body of one of the function's own instances
+                                return Err(Error::RecursiveDefinition(vec![name.clone(), name.clone()]));
+                            }
+                            let (specialized_name, t, function) = self.specialize(&generics[index].scope, &arg_types)?;
+                            if !generics[index].functions.iter().any(|(instance, _)| instance == &specialized_name) {
+                                // Check the instance for these argument types once, in the scope the
+                                // generic function is defined in, where later generics aren't visible
+                                generics[index].functions.push((specialized_name.clone(), None));
+                                let function_scope = generics[index].scope.clone();
+                                let mut later = generics.split_off(index + 1);
+                                let function = function.check_with(&function_scope, generics);
+                                generics.append(&mut later);
+                                let function = function?;
+                                for (instance, checked) in &mut generics[index].functions {
+                                    if instance == &specialized_name {
+                                        *checked = Some(function.clone());
+                                    }
+                                }
+                            }
+                            match t {
+                                Type::Function(_, ret) => (Node::Call(specialized_name, typed_args), *ret),
+                                _ => unreachable!()
+                            }
+                        }
                         _ => return Err(Error::CallNonFunction(name.clone())),
                     }
                 } else {
                     return Err(Error::VariableNotInScope(name.clone()));
                 }
             },
-            Self::Let(name, t, val, expr) => {
+            Self::Let(name, t, val, body) => {
+                let scope = bind(scope, name, t, val);
+                let typed_val = val.check_with(&scope, generics)?;
+                if &typed_val.t != t {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), t.clone(), typed_val.t));
+                }
+                return self.check_let(name, val, typed_val, body, &scope, generics);
+            }
+            Self::LetInfer(name, val, body) => {
+                // A value that refers to the new variable needs its type before it's checked:
+                // a function declares it, and anything else can only use the variable it shadows
+                let declared = match &**val {
+                    Self::Function(_, args, ret, _) => Some(Type::Function(args.iter().map(|(_, t)| t.clone()).collect(), Box::new(ret.clone()))),
+                    _ if val.free_names().contains(name) => scope.get(name).cloned(),
+                    _ => None,
+                };
+                let (typed_val, scope) = match declared {
+                    Some(t) => {
+                        let scope = bind(scope, name, &t, val);
+                        let typed_val = val.check_with(&scope, generics)?;
+                        if typed_val.t != t {
+                            return Err(Error::MismatchedTypes(Box::new(self.clone()), t, typed_val.t));
+                        }
+                        (typed_val, scope)
+                    }
+                    None => {
+                        let typed_val = val.check_with(scope, generics)?;
+                        let scope = bind(scope, name, &typed_val.t, val);
+                        (typed_val, scope)
+                    }
+                };
+                return self.check_let(name, val, typed_val, body, &scope, generics);
+            }
+            Self::Destructure(pattern, t, val, body) => {
+                // The names aren't in scope in the value
+                let val = val.check_with(scope, generics)?;
+                if let Some(t) = t {
+                    if &val.t != t {
+                        return Err(Error::MismatchedTypes(Box::new(self.clone()), t.clone(), val.t));
+                    }
+                }
+                unique_names(pattern.names())?;
                 let mut scope = scope.clone();
-                scope.insert(name.clone(), t.clone());
-                let val_type = val.get_type(&scope)?;
-                if &val_type != t {
-                    return Err(Error::MismatchedTypes(self.clone(), t.clone(), val_type));
+                let mut names = vec![];
+                for (name, t) in pattern.bindings(&val.t)? {
+                    scope = bind(&scope, &name, &t, &Self::None);
+                    names.push((name, t.get_size()?));
                 }
-                expr.get_type(&scope)?
+                let body = body.check_with(&scope, generics)?;
+                let t = body.t.clone();
+                (Node::Destructure(names, Box::new(val), Box::new(body)), t)
             }
-            Self::LetInfer(name, val, expr) => {
-                Self::Let(name.clone(), val.get_type(scope)?, val.clone(), expr.clone()).get_type(scope)?
+            Self::Global(name, t, val, body) | Self::Const(name, t, val, body) => {
+                let val = val.check_with(scope, generics)?;
+                if &val.t != t {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), t.clone(), val.t));
+                }
+                t.get_size()?;
+                let body = body.check(&bind_global(scope, name, t, matches!(self, Self::Const(..))))?;
+                let t = body.t.clone();
+                (Node::Global(global_name(name), Box::new(val), Box::new(body)), t)
             }
             Self::Assign(name, expr) => {
                 let var_type = scope.get(name).ok_or(Error::VariableNotInScope(name.clone()))?;
-                // let mut scope = scope.clone();
-                // scope.insert(name.clone(), t.clone());
-                let expr_type = expr.get_type(&scope)?;
-                if var_type != &expr_type {
-                    return Err(Error::MismatchedTypes(self.clone(), var_type.clone(), expr_type));
+                if scope.contains_key(&index_name(name)) || scope.contains_key(&env_name(name)) {
+                    return Err(Error::FunctionNotVariable(name.clone()));
+                } else if scope.contains_key(&const_name(name)) {
+                    return Err(Error::ConstNotVariable(name.clone()));
+                }
+                let expr = expr.check_with(scope, generics)?;
+                if var_type != &expr.t {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), var_type.clone(), expr.t));
                 }
-                Type::Void
+                (Node::Assign(address_name(scope, name), Box::new(expr)), Type::Void)
             }
 
             Self::Add(a, b) => {
-                match (a.get_type(scope)?, b.get_type(scope)?) {
+                let a = a.check_with(scope, generics)?;
+                let b = b.check_with(scope, generics)?;
+                let t = match (&a.t, &b.t) {
                     (Type::Integer, Type::Integer) => Type::Integer,
-                    (Type::Pointer(x), Type::Integer) => Type::Pointer(x),
-                    (a, b) => return Err(Error::MismatchedTypes(self.clone(), a, b)),
-                }
+                    (Type::Signed, Type::Signed) => Type::Signed,
+                    (Type::Pointer(x), Type::Integer) => Type::Pointer(x.clone()),
+                    _ => return Err(Error::MismatchedTypes(Box::new(self.clone()), a.t, b.t)),
+                };
+                (Node::Add(Box::new(a), Box::new(b)), t)
             }
 
             Self::Sub(a, b)
             | Self::Mul(a, b)
             | Self::Div(a, b) => {
-                let a_type = a.get_type(scope)?;
-                let b_type = b.get_type(scope)?;
-                if a_type != Type::Integer {
-                    return Err(Error::MismatchedTypes(self.clone(), Type::Integer, a_type));
+                let a = a.check_with(scope, generics)?;
+                let b = b.check_with(scope, generics)?;
+                if a.t != Type::Integer && a.t != Type::Signed {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Integer, a.t));
                 }
-                if b_type != Type::Integer {
-                    return Err(Error::MismatchedTypes(self.clone(), Type::Integer, b_type));
+                if b.t != a.t {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), a.t, b.t));
                 }
-                Type::Integer
+                let t = a.t.clone();
+                (match self {
+                    Self::Sub(_, _) => Node::Sub(Box::new(a), Box::new(b)),
+                    Self::Mul(_, _) => Node::Mul(Box::new(a), Box::new(b)),
+                    _ => Node::Div(Box::new(a), Box::new(b)),
+                }, t)
             }
 
             Self::And(a, b)
             | Self::Or(a, b) => {
-                let a_type = a.get_type(scope)?;
-                let b_type = b.get_type(scope)?;
-                if a_type != Type::Bool {
-                    return Err(Error::MismatchedTypes(self.clone(), Type::Bool, a_type));
+                let a = a.check_with(scope, generics)?;
+                let b = b.check_with(scope, generics)?;
+                if a.t != Type::Bool {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Bool, a.t));
                 }
-                if b_type != Type::Bool {
-                    return Err(Error::MismatchedTypes(self.clone(), Type::Bool, b_type));
+                if b.t != Type::Bool {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Bool, b.t));
                 }
-                Type::Bool
+                (if let Self::And(_, _) = self {
+                    Node::And(Box::new(a), Box::new(b))
+                } else {
+                    Node::Or(Box::new(a), Box::new(b))
+                }, Type::Bool)
             }
 
             Self::Not(x) => {
-                let x_type = x.get_type(scope)?;
-                if x_type != Type::Bool {
-                    return Err(Error::MismatchedTypes(self.clone(), Type::Bool, x_type));
+                let x = x.check_with(scope, generics)?;
+                if x.t != Type::Bool {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Bool, x.t));
                 }
-                Type::Bool
+                (Node::Not(Box::new(x)), Type::Bool)
+            }
+
+            Self::Cast(x, t) => {
+                let x = x.check_with(scope, generics)?;
+                match (&x.t, t) {
+                    (Type::Integer, Type::Signed)
+                    | (Type::Signed, Type::Integer)
+                    | (Type::Pointer(_), Type::Integer)
+                    | (Type::Integer, Type::Pointer(_))
+                    | (Type::Pointer(_), Type::Pointer(_)) => {},
+                    (a, b) if a == b || (a.is_scalar() && b.is_scalar()) => {},
+                    _ => return Err(Error::InvalidCast(Box::new(self.clone()), x.t, t.clone())),
+                }
+                (Node::Cast(Box::new(x)), t.clone())
             }
 
             Self::Putchar(x) => {
-                let x_type = x.get_type(scope)?;
-                if x_type != Type::Character {
-                    return Err(Error::MismatchedTypes(self.clone(), Type::Character, x_type));
+                let x = x.check_with(scope, generics)?;
+                if x.t != Type::Character {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Character, x.t));
                 }
-                Type::Void
+                (Node::Putchar(Box::new(x)), Type::Void)
             }
 
             Self::Putnum(x) => {
-                let x_type = x.get_type(scope)?;
-                if x_type != Type::Integer && !matches!(x_type, Type::Pointer(_)) {
-                    return Err(Error::MismatchedTypes(self.clone(), Type::Integer, x_type));
+                let x = x.check_with(scope, generics)?;
+                if x.t != Type::Integer && x.t != Type::Signed && !matches!(x.t, Type::Pointer(_)) {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), Type::Integer, x.t));
                 }
-                Type::Void
+                (Node::Putnum(Box::new(x)), Type::Void)
             }
 
-            Self::Getnum => Type::Integer,
-            Self::Getchar => Type::Character,
+            Self::Getnum => (Node::Getnum, Type::Integer),
+            Self::GetSigned => (Node::GetSigned, Type::Signed),
+            Self::Getchar => (Node::Getchar, Type::Character),
 
             Self::Eq(a, b) | Self::Neq(a, b) => {
-                let a_type = a.get_type(scope)?;
-                let b_type = b.get_type(scope)?;
-                if a_type != b_type {
-                    return Err(Error::MismatchedTypes(self.clone(), a_type, b_type));
+                let a = a.check_with(scope, generics)?;
+                let b = b.check_with(scope, generics)?;
+                if a.t != b.t {
+                    return Err(Error::MismatchedTypes(Box::new(self.clone()), a.t, b.t));
+                }
+
+                (if let Self::Eq(_, _) = self {
+                    Node::Eq(Box::new(a), Box::new(b))
+                } else {
+                    Node::Neq(Box::new(a), Box::new(b))
+                }, Type::Bool)
+            }
+        };
+        Typed::new(node, t)
+    }
+}
+
+/// A type checked expression, annotated with its type and the number
+/// of cells its value takes up on the stack
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Typed {
+    pub node: Node,
+    pub t: Type,
+    pub size: u32,
+}
+
+/// The type checked form of an expression. Syntax sugar like indexing
+/// and inferred `let`s is gone, and names are resolved to the macros
+/// that push their addresses.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Node {
+    Integer(u64),
+    Signed(i64),
+    Bool(bool),
+    Character(char),
+    None,
+
+    /// A function, with the sizes of its arguments and captured variables
+    Function(Vec<(String, u32)>, Vec<mir::Capture>, Box<Typed>),
+    /// A generic function, which is only compiled through its instances
+    Generic,
+    /// The instances of a generic function that are called in the body
+    LetGeneric(Vec<(String, Typed)>, Box<Typed>),
+    Let(String, Box<Typed>, Box<Typed>),
+    /// A tuple whose members are stored as separate variables, with their sizes
+    Destructure(Vec<(String, u32)>, Box<Typed>, Box<Typed>),
+    /// A function that doesn't capture anything, so it has an index
+    LetFunction(String, Box<Typed>, Box<Typed>),
+    /// A closure, with the size of its environment
+    LetClosure(String, u32, Box<Typed>, Box<Typed>),
+    Global(String, Box<Typed>, Box<Typed>),
+    Assign(String, Box<Typed>),
+
+    /// Call a function or closure by name
+    Call(String, Vec<Typed>),
+    /// Call a function value through its index
+    CallIndirect(Box<Typed>, Vec<Typed>),
+    Variable(String),
+    FunctionIndex(String),
+    Increment(String),
+    Decrement(String),
+
+    Refer(String),
+    Deref(Box<Typed>),
+    DerefAssign(Box<Typed>, Box<Typed>),
+
+    Tuple(Vec<Typed>),
+    /// A member of a tuple, with the size of the members before it
+    Nth(Box<Typed>, u32),
+
+    Add(Box<Typed>, Box<Typed>),
+    Sub(Box<Typed>, Box<Typed>),
+    Mul(Box<Typed>, Box<Typed>),
+    Div(Box<Typed>, Box<Typed>),
+
+    Or(Box<Typed>, Box<Typed>),
+    And(Box<Typed>, Box<Typed>),
+    Not(Box<Typed>),
+    Cast(Box<Typed>),
+
+    Getchar,
+    Getnum,
+    GetSigned,
+    Putchar(Box<Typed>),
+    Putnum(Box<Typed>),
+
+    Free(Box<Typed>),
+    /// Allocate cells for some number of values of a size
+    Alloc(Box<Typed>, u32, Option<Vec<Typed>>),
+    /// Resize an allocation to some number of values of a size
+    Realloc(Box<Typed>, Box<Typed>, u32),
+    /// Copy some number of values of a size from one pointer to another
+    Memcpy(Box<Typed>, Box<Typed>, Box<Typed>, u32),
+    Memset(Box<Typed>, Box<Typed>, Box<Typed>),
+    /// Constants stored in static data, with the expressions they came
+    /// from to report any that don't compile to constant cells
+    Array(Vec<(Expr, Typed)>),
+
+    Block(Vec<Typed>),
+
+    While(Box<Typed>, Box<Typed>),
+    If(Box<Typed>, Box<Typed>),
+
+    Eq(Box<Typed>, Box<Typed>),
+    Neq(Box<Typed>, Box<Typed>),
+}
+
+impl Typed {
+    fn new(node: Node, t: Type) -> Result<Self, Error> {
+        // Generic functions don't exist until they're called
+        let size = if let Type::Generic(_, _) = t { 0 } else { t.get_size()? };
+        Ok(Self { node, t, size })
+    }
+
+    /// Whether this is a literal, or a tuple of literals
+    fn is_constant(&self) -> bool {
+        match &self.node {
+            Node::Integer(_) | Node::Signed(_) | Node::Bool(_) | Node::Character(_) | Node::None => true,
+            Node::Tuple(items) => items.iter().all(Self::is_constant),
+            _ => false,
+        }
+    }
+
+    /// Whether this is a zero that a value can be compared against
+    /// by checking if the value is nonzero
+    fn is_zero(&self) -> bool {
+        self.node == Node::Integer(0) || self.node == Node::Character('\0')
+    }
+
+    /// Compile the condition of an `if` or `while`. A condition only has
+    /// to be nonzero to be true, so comparisons with zero and the results
+    /// of `&&` and `||` aren't turned into 0 or 1 first.
+    fn compile_condition(&self, offset: &mut u32, functions: &mut u32, target: &Target) -> Result<Op, Error> {
+        Ok(match &self.node {
+            Node::Neq(a, b) if a.t != Type::Signed && b.is_zero() => a.compile(offset, functions, target)?,
+            Node::Neq(a, b) if a.t != Type::Signed && a.is_zero() => b.compile(offset, functions, target)?,
+            Node::And(a, b) => Op::Do(vec![
+                a.compile_condition(offset, functions, target)?,
+                Op::If(vec![Op::Duplicate], vec![
+                    Op::Stfree(1),
+                    b.compile_condition(offset, functions, target)?,
+                ]),
+            ]),
+            Node::Or(a, b) => Op::Do(vec![
+                a.compile_condition(offset, functions, target)?,
+                Op::If(vec![Op::Duplicate, Op::Not], vec![
+                    Op::Stfree(1),
+                    b.compile_condition(offset, functions, target)?,
+                ]),
+            ]),
+            _ => self.compile(offset, functions, target)?,
+        })
+    }
+
+    /// Compile a typed expression to MIR. `offset` is the size of the
+    /// current frame's variables, and `functions` is the number of
+    /// functions that have been given an index so far.
+    pub fn compile(&self, offset: &mut u32, functions: &mut u32, target: &Target) -> Result<Op, Error> {
+        Ok(match &self.node {
+            Node::Tuple(items) => {
+                let mut result = vec![];
+                for item in items {
+                    result.push(item.compile(offset, functions, target)?)
                 }
+                Op::Do(result)
+            }
+
+            Node::Increment(name) => {
+                Op::Do(vec![
+                    Op::Macro(name.clone()),
+                    Op::Increment(SP.deref().deref(), 1),
+                    Op::Stfree(1),
+                ])
+            }
+
+            Node::Decrement(name) => {
+                Op::Do(vec![
+                    Op::Macro(name.clone()),
+                    Op::Decrement(SP.deref().deref(), 1),
+                    Op::Stfree(1),
+                ])
+            }
 
-                if matches!(a_type, Type::Tuple(_)) {
-                    return Err(Error::CmpOfTuple(self.clone(), a_type));
+            Node::Nth(tup, size_before) => {
+                Op::Do(vec![
+                    tup.compile(offset, functions, target)?,
+                    // Remove the members after this one, then the members before it
+                    Op::Stfree(tup.size - size_before - self.size),
+                    Op::Slide(self.size, *size_before),
+                ])
+            }
+
+            Node::Refer(name) => Op::Macro(name.clone()),
+            Node::Deref(value) => {
+                Op::Do(vec![
+                    value.compile(offset, functions, target)?,
+                    Op::Load(self.size)
+                ])
+            }
+            Node::DerefAssign(addr, value) => {
+                Op::Do(vec![
+                    value.compile(offset, functions, target)?,
+                    addr.compile(offset, functions, target)?,
+                    Op::Store(value.size)
+                ])
+            }
+
+            Node::Block(items) => {
+                let mut ops = vec![];
+                for (i, value) in items.iter().enumerate() {
+                    ops.push(value.compile(offset, functions, target)?);
+                    if value.size > 0 && i < items.len() - 1 {
+                        ops.push(Op::Stfree(value.size));
+                    }
                 }
+                Op::Do(ops)
+            }
+
+            Node::Assign(name, expr) => {
+                Op::Do(vec![
+                    expr.compile(offset, functions, target)?,
+                    Op::Macro(name.clone()),
+                    Op::Store(expr.size)
+                ])
+            }
+
+            Node::If(cond, body) => {
+                Op::If(vec![cond.compile_condition(offset, functions, target)?], vec![body.compile(offset, functions, target)?])
+            }
 
-                Type::Bool
+            Node::While(cond, body) => {
+                Op::While(vec![cond.compile_condition(offset, functions, target)?], vec![body.compile(offset, functions, target)?])
+            }
+
+            Node::None | Node::Generic => Op::Do(vec![]),
+            Node::Integer(i) => Op::PushLiteral(literal(&Expr::Integer(*i), *i, target)?),
+            Node::Signed(i) => Op::Do(vec![
+                Op::PushLiteral(Literal((*i < 0) as u64)),
+                Op::PushLiteral(literal(&Expr::Signed(*i), i.unsigned_abs(), target)?),
+            ]),
+            Node::Bool(b) => Op::PushLiteral(Literal(*b as u64)),
+            Node::Character(ch) => Op::PushLiteral(literal(&Expr::Character(*ch), *ch as u8 as u64, target)?),
+
+            Node::Putchar(x) => Op::Do(vec![
+                x.compile(offset, functions, target)?,
+                Op::Putchar
+            ]),
+            Node::Putnum(x) => Op::Do(vec![
+                x.compile(offset, functions, target)?,
+                if x.t == Type::Signed { Op::SignedPutnum } else { Op::Putnum }
+            ]),
+            Node::Array(items) => {
+                let mut cells = vec![];
+                for (expr, item) in items {
+                    let code = item.compile(offset, functions, target)?;
+                    cells.extend(constant_cells(&code).ok_or_else(|| Error::NonConstantArray(expr.clone()))?);
+                }
+                Op::Static("array".to_string(), cells, vec![Op::Macro("array".to_string())])
+            }
+            Node::Alloc(x, size, vals) => Op::Do(vec![
+                x.compile(offset, functions, target)?,
+                Op::PushLiteral(Literal(*size as u64)),
+                Op::Mul,
+                Op::Alloc,
+                Op::Do(if let Some(vals) = vals {
+                    // Copy the pointer from beneath the values to store them
+                    let mut result = vec![];
+                    let mut size = 0;
+                    for val in vals {
+                        size += val.size;
+                        result.push(val.compile(offset, functions, target)?)
+                    }
+                    result.extend([
+                        Op::Push(SP.deref().offset(-(size as i32) - 1)),
+                        Op::Store(size),
+                    ]);
+
+                    result
+                } else {
+                    vec![]
+                })
+            ]),
+            Node::Realloc(ptr, n, size) => Op::Do(vec![
+                ptr.compile(offset, functions, target)?,
+                n.compile(offset, functions, target)?,
+                Op::PushLiteral(Literal(*size as u64)),
+                Op::Mul,
+                Op::Realloc,
+            ]),
+            Node::Memcpy(dst, src, n, size) => Op::Do(vec![
+                dst.compile(offset, functions, target)?,
+                src.compile(offset, functions, target)?,
+                n.compile(offset, functions, target)?,
+                Op::PushLiteral(Literal(*size as u64)),
+                Op::Mul,
+                // Copy a cell at a time, moving both pointers along,
+                // until there are no cells left
+                Op::While(vec![Op::Duplicate], vec![
+                    Op::Push(SP.deref().offset(-2)),
+                    Op::Load(1),
+                    Op::Push(SP.deref().offset(-4)),
+                    Op::Store(1),
+                    Op::Increment(SP.deref().offset(-2), 1),
+                    Op::Increment(SP.deref().offset(-1), 1),
+                    Op::Decrement(SP.deref(), 1),
+                ]),
+                Op::Stfree(3),
+            ]),
+            Node::Memset(dst, val, n) => {
+                // The value is between the pointer and the count
+                let size = val.size as i32;
+                Op::Do(vec![
+                    dst.compile(offset, functions, target)?,
+                    val.compile(offset, functions, target)?,
+                    n.compile(offset, functions, target)?,
+                    Op::While(vec![Op::Duplicate], vec![
+                        Op::Do((0..size).map(|_| Op::Push(SP.deref().offset(-size - 1))).collect()),
+                        Op::Push(SP.deref().offset(-2 * size - 2)),
+                        Op::Store(val.size),
+                        Op::Increment(SP.deref().offset(-size - 1), val.size),
+                        Op::Decrement(SP.deref(), 1),
+                    ]),
+                    Op::Stfree(val.size + 2),
+                ])
+            }
+            Node::Free(x) => Op::Do(vec![
+                x.compile(offset, functions, target)?,
+                Op::Free
+            ]),
+            Node::Getchar => Op::Getchar,
+            Node::Getnum => Op::Getnum,
+            Node::GetSigned => Op::SignedGetnum,
+
+            Node::Function(args, env, body) => {
+                // Local variables in the function are stored after the arguments and environment
+                let mut offset = args.iter().map(|(_, size)| size).sum::<u32>();
+                for capture in env {
+                    offset += match capture {
+                        mir::Capture::Value(_, size) => *size,
+                        mir::Capture::Reference(_) => 1,
+                    };
+                }
+                function(
+                    args.clone(),
+                    env.clone(),
+                    body.size,
+                    vec![body.compile(&mut offset, functions, target)?]
+                )
+            },
+
+            Node::Add(a, b) => {
+                Op::Do(vec![
+                    a.compile(offset, functions, target)?,
+                    b.compile(offset, functions, target)?,
+                    if a.t == Type::Signed { Op::SignedAdd } else { Op::Add }
+                ])
+            }
+
+            Node::Sub(a, b) => {
+                Op::Do(vec![
+                    a.compile(offset, functions, target)?,
+                    b.compile(offset, functions, target)?,
+                    if a.t == Type::Signed { Op::SignedSub } else { Op::Sub }
+                ])
+            }
+
+            Node::Mul(a, b) => {
+                Op::Do(vec![
+                    a.compile(offset, functions, target)?,
+                    b.compile(offset, functions, target)?,
+                    if a.t == Type::Signed { Op::SignedMul } else { Op::Mul }
+                ])
+            }
+
+            Node::Div(a, b) => {
+                Op::Do(vec![
+                    a.compile(offset, functions, target)?,
+                    b.compile(offset, functions, target)?,
+                    if a.t == Type::Signed { Op::SignedDiv } else { Op::Div }
+                ])
+            }
+
+            // The right side is only evaluated if the left side doesn't
+            // decide the result. Booleans are always 0 or 1, so the result
+            // is whichever side was evaluated last.
+            Node::And(a, b) => {
+                Op::Do(vec![
+                    a.compile(offset, functions, target)?,
+                    Op::If(vec![Op::Duplicate], vec![
+                        Op::Stfree(1),
+                        b.compile(offset, functions, target)?,
+                    ]),
+                ])
+            }
+
+            Node::Or(a, b) => {
+                Op::Do(vec![
+                    a.compile(offset, functions, target)?,
+                    Op::If(vec![Op::Duplicate, Op::Not], vec![
+                        Op::Stfree(1),
+                        b.compile(offset, functions, target)?,
+                    ]),
+                ])
+            }
+
+            Node::Not(x) => {
+                Op::Do(vec![
+                    x.compile(offset, functions, target)?,
+                    Op::Not
+                ])
+            }
+
+            Node::Cast(x) => {
+                Op::Do(match (&x.t, &self.t) {
+                    (Type::Integer, Type::Signed) => vec![
+                        Op::PushLiteral(Literal(0)),
+                        x.compile(offset, functions, target)?,
+                    ],
+                    // Negative integers wrap around like any other
+                    // subtraction below zero on the target
+                    (Type::Signed, Type::Integer) => vec![
+                        x.compile(offset, functions, target)?,
+                        Op::Push(SP.deref().offset(-2)),
+                        Op::If(vec![], vec![
+                            Op::PushLiteral(Literal(0)),
+                            Op::Push(SP.deref().offset(-2)),
+                            Op::Sub,
+                            Op::Slide(1, 1),
+                        ]),
+                        Op::Slide(1, 1),
+                    ],
+                    // Booleans are always 0 or 1
+                    (x_type, Type::Bool) if x_type != &Type::Bool => vec![
+                        x.compile(offset, functions, target)?,
+                        Op::Not,
+                        Op::Not,
+                    ],
+                    // Everything else has the same representation
+                    _ => vec![x.compile(offset, functions, target)?],
+                })
+            }
+
+            Node::Eq(a, b) => {
+                if let Type::Tuple(_) = a.t {
+                    // Tuples are equal if each of their cells is
+                    Op::Do(vec![
+                        a.compile(offset, functions, target)?,
+                        b.compile(offset, functions, target)?,
+                        Op::EqN(a.size)
+                    ])
+                } else if a.t == Type::Signed {
+                    Op::Do(vec![
+                        b.compile(offset, functions, target)?,
+                        a.compile(offset, functions, target)?,
+                        Op::SignedEq
+                    ])
+                } else if b.node == Node::Integer(0) {
+                    Op::Do(vec![
+                        a.compile(offset, functions, target)?,
+                        Op::Not
+                    ])
+                } else if a.node == Node::Integer(0) {
+                    Op::Do(vec![
+                        b.compile(offset, functions, target)?,
+                        Op::Not
+                    ])
+                } else {
+                    Op::Do(vec![
+                        b.compile(offset, functions, target)?,
+                        a.compile(offset, functions, target)?,
+                        Op::Eq
+                    ])
+                }
+            }
+
+            Node::Neq(a, b) => {
+                if let Type::Tuple(_) = a.t {
+                    Op::Do(vec![
+                        a.compile(offset, functions, target)?,
+                        b.compile(offset, functions, target)?,
+                        Op::EqN(a.size),
+                        Op::Not
+                    ])
+                } else if a.t == Type::Signed {
+                    Op::Do(vec![
+                        b.compile(offset, functions, target)?,
+                        a.compile(offset, functions, target)?,
+                        Op::SignedEq,
+                        Op::Not
+                    ])
+                } else if b.is_zero() || a.is_zero() {
+                    // A value is different from zero if it's nonzero,
+                    // which is turned into 1 so the result is a boolean
+                    Op::Do(vec![
+                        if b.is_zero() { a.compile(offset, functions, target)? } else { b.compile(offset, functions, target)? },
+                        Op::Not,
+                        Op::Not
+                    ])
+                } else {
+                    Op::Do(vec![
+                        b.compile(offset, functions, target)?,
+                        a.compile(offset, functions, target)?,
+                        Op::Neq
+                    ])
+                }
+            }
+
+            Node::FunctionIndex(name) => Op::Macro(name.clone()),
+
+            Node::Variable(name) => {
+                Op::Do(vec![
+                    Op::Macro(name.clone()),
+                    Op::Load(self.size)
+                ])
+            }
+
+            Node::LetFunction(name, function, body) => {
+                // Register the function so it can be called through its index
+                *functions += 1;
+                let index = literal(&Expr::Integer(*functions as u64), *functions as u64, target)?;
+                let key = format!("{}#{}", function.t, index);
+                Op::Let(key.clone(), vec![
+                    function.compile(offset, functions, target)?
+                ], vec![
+                    Op::Let(name.clone(), vec![Op::Macro(key)], vec![
+                        Op::Let(index_name(name), vec![Op::PushLiteral(index)], vec![
+                            body.compile(offset, functions, target)?,
+                        ])
+                    ])
+                ])
+            }
+
+            Node::LetGeneric(instances, body) => {
+                // Each instance is defined once, however many times it's called
+                let mut definitions = vec![];
+                for (name, function) in instances {
+                    definitions.push((name.clone(), function.compile(offset, functions, target)?));
+                }
+                let mut result = body.compile(offset, functions, target)?;
+                for (name, function) in definitions.into_iter().rev() {
+                    result = Op::Let(name, vec![function], vec![result]);
+                }
+                result
+            }
+
+            Node::LetClosure(name, env_size, function, body) => {
+                // Push the environment after the arguments
+                Op::Let(name.clone(), vec![
+                    Op::Macro(env_name(name)),
+                    Op::Load(*env_size),
+                    function.compile(offset, functions, target)?
+                ], vec![
+                    body.compile(offset, functions, target)?,
+                ])
+            }
+
+            Node::Let(name, val, body) => {
+                let this_offset = *offset;
+                *offset += val.size;
+                let val_result = val.compile(offset, functions, target)?;
+                let body_result = body.compile(offset, functions, target)?;
+                *offset -= val.size;
+
+                store_variable(name, val.size, vec![
+                    Op::LoadFrom(FP, 1),
+                    Op::PushLiteral(Literal(this_offset as u64)),
+                    Op::Add
+                ], val_result, body_result, body.size)
+            }
+
+            Node::Destructure(names, val, body) => {
+                let this_offset = *offset;
+                *offset += val.size;
+                let val_result = val.compile(offset, functions, target)?;
+                let body_result = body.compile(offset, functions, target)?;
+                *offset -= val.size;
+
+                // The value's cells are already in the order of the variables,
+                // so each one is stored straight into its own slot, last first
+                let mut code = vec![];
+                for (name, size) in names.iter().rev() {
+                    code.push(Op::Macro(name.clone()));
+                    code.push(Op::Store(*size));
+                }
+                code.push(body_result);
+                let mut address = this_offset + names.iter().map(|(_, size)| size).sum::<u32>();
+                for (name, size) in names.iter().rev() {
+                    address -= size;
+                    code = vec![Op::Let(name.clone(), vec![
+                        Op::LoadFrom(FP, 1),
+                        Op::PushLiteral(Literal(address as u64)),
+                        Op::Add
+                    ], code)];
+                }
+
+                // The value is evaluated before the names are in scope
+                Op::Do(vec![
+                    Op::Stalloc(val.size),
+                    val_result,
+                    Op::Do(code),
+                    Op::Slide(body.size, val.size),
+                ])
+            }
+
+            Node::Global(name, val, body) => {
+                // Globals are stored in static data, so their address is fixed,
+                // and functions can use them no matter where they're called.
+                // Constant values are stored there before the program runs.
+                let val_result = val.compile(offset, functions, target)?;
+                let body_result = body.compile(offset, functions, target)?;
+                match constant_cells(&val_result) {
+                    Some(cells) => Op::Static(name.clone(), cells, vec![body_result]),
+                    None => Op::Static(name.clone(), vec![Literal(0); val.size as usize], vec![
+                        val_result,
+                        Op::Macro(name.clone()),
+                        Op::Store(val.size),
+                        body_result,
+                    ]),
+                }
+            }
+
+            Node::Call(name, args) => {
+                let mut result = vec![];
+                for arg in args {
+                    result.push(arg.compile(offset, functions, target)?);
+                }
+                result.push(Op::Macro(name.clone()));
+                Op::Do(result)
+            }
+
+            Node::CallIndirect(function, args) => {
+                let mut result = vec![];
+                for arg in args {
+                    result.push(arg.compile(offset, functions, target)?);
+                }
+                result.push(function.compile(offset, functions, target)?);
+                result.push(Op::CallIndirect(function.t.to_string()));
+                Op::Do(result)
             }
         })
     }
-}
\ No newline at end of file
+}
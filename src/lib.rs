@@ -4,4 +4,30 @@ pub mod hir;
 pub mod mir;
 pub mod lir;
 pub mod error;
+pub mod target;
+pub mod module;
+pub mod opt;
 
+
+use std::{collections::BTreeMap, path::Path};
+use target::Target;
+
+/// Compile a Harbor source file, and the files it imports, for a target
+pub fn compile_hir(path: impl AsRef<Path>, code: impl ToString, target: &Target) -> Result<lir::Program, hir::Error> {
+    let mut program = lir::Program::default();
+
+    let w = module::load(path, code.to_string())?;
+    // Fold before type checking, so the one typed tree that's checked is the one that's compiled
+    let w = w.fold(&BTreeMap::new(), target);
+    let w = w.check(&BTreeMap::new())?.compile(&mut 0, &mut 0, target)?;
+    let w = opt::PassManager::default().run(w);
+    let w = opt::RegisterAllocator.run(w);
+    match w.assemble(&mut program) {
+        Ok(()) => {
+            Ok(program.optimize())
+        }
+        Err(e) => {
+            Err(hir::Error::MIRError(e))
+        }
+    }
+}
@@ -1,12 +1,14 @@
 
 use core::fmt;
+use std::io::{BufRead, Write};
+use super::target::{Overflow, Target};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Op {
     Comment(char),
 
-    Plus(u32),
-    Minus(u32),
+    Plus(u64),
+    Minus(u64),
     Left(u32),
     Right(u32),
     Loop,
@@ -21,7 +23,8 @@ pub enum Op {
     Deref,
 
     Alloc,
-    Free
+    Free,
+    Realloc
 }
 
 impl fmt::Display for Op {
@@ -30,11 +33,11 @@ impl fmt::Display for Op {
             Op::Comment(c) => write!(f, "{}", c),
 
             Op::Plus(n) if n > 1 => write!(f, "+({})", n),
-            Op::Plus(n) if n == 1 => write!(f, "+"),
+            Op::Plus(1) => write!(f, "+"),
             Op::Plus(_) => {Ok(())},
 
             Op::Minus(n) if n > 1 => write!(f, "-({})", n),
-            Op::Minus(n) if n == 1 => write!(f, "-"),
+            Op::Minus(1) => write!(f, "-"),
             Op::Minus(_) => {Ok(())},
 
             Op::Left(n) if n > 1 => write!(f, "<({})", n),
@@ -56,6 +59,7 @@ impl fmt::Display for Op {
             
             Op::Alloc => write!(f, "?"),
             Op::Free => write!(f, "!"),
+            Op::Realloc => write!(f, "~"),
         }
     }
 }
@@ -79,11 +83,11 @@ impl Program {
         self.0.push(Op::End);
     }
 
-    pub fn plus(&mut self, n: u32) {
+    pub fn plus(&mut self, n: u64) {
         self.0.push(Op::Plus(n));
     }
 
-    pub fn minus(&mut self, n: u32) {
+    pub fn minus(&mut self, n: u64) {
         self.0.push(Op::Minus(n));
     }
 
@@ -127,6 +131,10 @@ impl Program {
         self.0.push(Op::Free);
     }
 
+    pub fn realloc(&mut self) {
+        self.0.push(Op::Realloc);
+    }
+
     pub fn put(&mut self) {
         self.0.push(Op::Put);
     }
@@ -165,6 +173,7 @@ impl Program {
 
                 Op::Alloc => result += "?",
                 Op::Free => result += "!",
+                Op::Realloc => result += "~",
             }
         }
         result
@@ -206,6 +215,240 @@ impl Program {
     }
 }
 
+/// The state of a program being run by the interpreter
+struct Machine<'a, R: BufRead, W: Write> {
+    target: &'a Target,
+    tape: Vec<u64>,
+    /// For each allocated cell, the number of cells left in its allocation
+    taken: Vec<u64>,
+    /// The pointers saved by each `Deref`, to be restored by `Refer`
+    refs: Vec<usize>,
+    ptr: usize,
+    input: R,
+    output: W,
+}
+
+impl<'a, R: BufRead, W: Write> Machine<'a, R, W> {
+    fn cell(&mut self) -> Result<&mut u64, String> {
+        self.tape.get_mut(self.ptr).ok_or_else(|| format!("pointer {} is off the tape", self.ptr))
+    }
+
+    fn plus(&mut self, n: u64) -> Result<(), String> {
+        let max = self.target.cell_width.max_value();
+        let overflow = self.target.overflow;
+        let cell = self.cell()?;
+        let n = n & max;
+        *cell = if *cell > max - n {
+            match overflow {
+                Overflow::Wrap => (*cell).wrapping_add(n) & max,
+                Overflow::Saturate => max,
+                Overflow::Trap => return Err("overflow".to_string()),
+            }
+        } else {
+            *cell + n
+        };
+        Ok(())
+    }
+
+    fn minus(&mut self, n: u64) -> Result<(), String> {
+        let max = self.target.cell_width.max_value();
+        let overflow = self.target.overflow;
+        let cell = self.cell()?;
+        let n = n & max;
+        *cell = if *cell < n {
+            match overflow {
+                Overflow::Wrap => (*cell).wrapping_sub(n) & max,
+                Overflow::Saturate => 0,
+                Overflow::Trap => return Err("underflow".to_string()),
+            }
+        } else {
+            *cell - n
+        };
+        Ok(())
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>, String> {
+        let buf = self.input.fill_buf().map_err(|e| e.to_string())?;
+        Ok(buf.first().copied())
+    }
+
+    /// Read a character, or zero at the end of the input
+    fn get(&mut self) -> Result<u64, String> {
+        Ok(match self.peek()? {
+            Some(ch) => {
+                self.input.consume(1);
+                ch as u64
+            }
+            None => 0,
+        })
+    }
+
+    /// Read a decimal number, skipping any whitespace before it.
+    /// If there isn't a number, the last number read is returned.
+    fn getnum(&mut self, last: i64) -> Result<i64, String> {
+        while let Some(ch) = self.peek()? {
+            if !ch.is_ascii_whitespace() { break }
+            self.input.consume(1);
+        }
+        let negative = match self.peek()? {
+            Some(ch @ (b'-' | b'+')) => {
+                self.input.consume(1);
+                ch == b'-'
+            }
+            _ => false,
+        };
+        let mut result: Option<i64> = None;
+        while let Some(ch @ b'0'..=b'9') = self.peek()? {
+            self.input.consume(1);
+            result = Some(result.unwrap_or(0).wrapping_mul(10).wrapping_add((ch - b'0') as i64));
+        }
+        Ok(match result {
+            Some(n) if negative => n.wrapping_neg(),
+            Some(n) => n,
+            None => last,
+        })
+    }
+
+    /// Allocate the number of cells in the current cell, searching
+    /// from the end of the tape for enough consecutive free cells
+    fn alloc(&mut self) -> Result<(), String> {
+        let requested = *self.cell()?;
+        *self.cell()? = self.allocate(requested)?;
+        Ok(())
+    }
+
+    /// Find a block of free cells, and return its address. Cell zero is
+    /// never allocated, so it's the address of every empty allocation.
+    fn allocate(&mut self, requested: u64) -> Result<u64, String> {
+        if requested == 0 {
+            return Ok(0)
+        }
+        let mut free = 0;
+        for i in (1..self.tape.len()).rev() {
+            if self.taken[i] == 0 {
+                free += 1;
+            } else {
+                free = 0;
+            }
+            if free >= requested {
+                for j in 0..requested {
+                    self.taken[i + j as usize] = requested - j;
+                }
+                return Ok(i as u64)
+            }
+        }
+        Err("no free memory".to_string())
+    }
+
+    /// Free the allocation whose address is in the current cell
+    fn free(&mut self) -> Result<(), String> {
+        let address = *self.cell()? as usize;
+        let size = *self.taken.get(address).ok_or_else(|| format!("cannot free address {}", address))?;
+        for i in address..address + size as usize {
+            self.taken[i] = 0;
+            self.tape[i] = 0;
+        }
+        Ok(())
+    }
+
+    /// Move the allocation whose address is in the current cell to a block
+    /// with the number of cells in the next cell, keeping as much of its
+    /// contents as fits. The current cell is set to the new address.
+    fn realloc(&mut self) -> Result<(), String> {
+        let address = *self.cell()? as usize;
+        let size = *self.taken.get(address).ok_or_else(|| format!("cannot reallocate address {}", address))? as usize;
+        let contents = self.tape[address..address + size].to_vec();
+        self.free()?;
+        let requested = *self.tape.get(self.ptr + 1).ok_or("pointer is off the tape")?;
+        let new_address = self.allocate(requested)? as usize;
+        for (i, cell) in contents.into_iter().take(requested as usize).enumerate() {
+            self.tape[new_address + i] = cell;
+        }
+        *self.cell()? = new_address as u64;
+        Ok(())
+    }
+}
+
+impl Program {
+    /// Run the program on a target, reading from `input` and writing
+    /// to `output`. This behaves the same as the program compiled to C.
+    pub fn run(&self, target: &Target, input: impl BufRead, output: impl Write) -> Result<(), String> {
+        let code = self.clone().optimize().0;
+
+        // Find the matching `End` for every `Loop`, and vice versa
+        let mut jumps = vec![0; code.len()];
+        let mut loops = vec![];
+        for (i, op) in code.iter().enumerate() {
+            match op {
+                Op::Loop => loops.push(i),
+                Op::End => {
+                    let start = loops.pop().ok_or("unmatched `]`")?;
+                    jumps[start] = i;
+                    jumps[i] = start;
+                }
+                _ => {}
+            }
+        }
+        if !loops.is_empty() {
+            return Err("unmatched `[`".to_string())
+        }
+
+        let size = target.tape_size() as usize;
+        let mut machine = Machine {
+            target,
+            tape: vec![0; size],
+            taken: vec![0; size],
+            refs: vec![],
+            ptr: 0,
+            input,
+            output,
+        };
+        let max = target.cell_width.max_value();
+        let mut num = 0;
+        let mut i = 0;
+        while i < code.len() {
+            match code[i] {
+                Op::Comment(_) => {}
+                Op::Plus(n) => machine.plus(n)?,
+                Op::Minus(n) => machine.minus(n)?,
+                Op::Right(n) => machine.ptr += n as usize,
+                Op::Left(n) => {
+                    machine.ptr = machine.ptr.checked_sub(n as usize)
+                        .ok_or("pointer moved off the start of the tape")?
+                }
+                Op::Loop => if *machine.cell()? == 0 { i = jumps[i] },
+                Op::End => if *machine.cell()? != 0 { i = jumps[i] },
+
+                Op::Put => {
+                    let ch = *machine.cell()? as u8;
+                    machine.output.write_all(&[ch]).map_err(|e| e.to_string())?;
+                }
+                Op::Get => *machine.cell()? = machine.get()?,
+                Op::Putnum => {
+                    let n = *machine.cell()?;
+                    write!(machine.output, "{}", n).map_err(|e| e.to_string())?;
+                }
+                Op::Getnum => {
+                    num = machine.getnum(num)?;
+                    *machine.cell()? = num as u64 & max;
+                }
+
+                Op::Deref => {
+                    machine.refs.push(machine.ptr);
+                    machine.ptr = *machine.cell()? as usize;
+                }
+                Op::Refer => machine.ptr = machine.refs.pop().ok_or("`&` without a matching `*`")?,
+
+                Op::Alloc => machine.alloc()?,
+                Op::Free => machine.free()?,
+                Op::Realloc => machine.realloc()?,
+            }
+            i += 1;
+        }
+        machine.output.flush().map_err(|e| e.to_string())
+    }
+}
+
 impl From<&str> for Program {
     fn from(s: &str) -> Self {
         let mut result = vec![];
@@ -225,6 +468,7 @@ impl From<&str> for Program {
                 '&' => result.push(Op::Refer),
                 '?' => result.push(Op::Alloc),
                 '!' => result.push(Op::Free),
+                '~' => result.push(Op::Realloc),
                 _ => {}
             }
         }
@@ -1,44 +1,12 @@
-use harborc::{hir, mir, lir};
+use harborc::{compile_hir, mir, lir, target::{Target, CellWidth, Overflow}};
 use std::collections::BTreeMap;
 use clap::{clap_app, crate_authors, crate_version, crate_description, AppSettings::ArgRequiredElseHelp};
 
-fn compile_hir(code: impl ToString) -> Result<lir::Program, hir::Error>{
-    let mut program = lir::Program::default();
-    use mir::*;
-    SP.set(mir::TOTAL_REGISTERS, &mut program);
-    FP.set(TOTAL_REGISTERS, &mut program);
-
-    let w = hir::parse(code.to_string())?;
-    let w = w.compile(&BTreeMap::new(), &mut 0)?;
-    match w.assemble(&mut program) {
-        Ok(()) => {
-            Ok(program.optimize())
-        }
-        Err(e) => {
-            Err(hir::Error::MIRError(e))
-        }
-    }
-}
-
 fn assemble_mir(code: impl ToString) -> Result<String, mir::Error>{
     let mut program = lir::Program::default();
     use mir::*;
-    SP.set(mir::TOTAL_REGISTERS, &mut program);
-    FP.set(TOTAL_REGISTERS, &mut program);
 
     let mut scope = BTreeMap::new();
-    scope.insert("putnum".to_string(), vec![
-        Op::Putnum
-    ]);
-    scope.insert("putchar".to_string(), vec![
-        Op::Putchar
-    ]);
-    scope.insert("getnum".to_string(), vec![
-        Op::Getnum
-    ]);
-    scope.insert("getchar".to_string(), vec![
-        Op::Getchar
-    ]);
     scope.insert("dec".to_string(), vec![
         Op::Decrement(SP.deref().deref(), 1),
         Op::Pop(TMP2)
@@ -49,29 +17,57 @@ fn assemble_mir(code: impl ToString) -> Result<String, mir::Error>{
     ]);
 
     let w = mir::parse(code)?;
-    w.assemble_with_scope(&scope, &mut program)?;
+    w.assemble_program(&scope, &mut program)?;
     Ok(program.optimize().to_string())
 }
 
-fn assemble_lir(code: lir::Program) -> String {
-    let mut result = String::from("#include <stdio.h>\n#include <stdlib.h>\n\n#define TAPE_SIZE 30000\nvoid panic(char *msg) {\n    fprintf(stderr, \"panic: %s\\n\", msg);\n    exit(-1);\n}\nvoid print_tape(unsigned int *tape, unsigned int *taken_cells, unsigned int size) { for (unsigned int i = 0; i < size; i++) { printf(\"%u \", tape[i]); } printf(\"\\n\"); int unfreed = 0; for (unsigned int i=0; i < TAPE_SIZE; i++) {unfreed += taken_cells[i]; i += taken_cells[i];} printf(\"%d unfreed\\n\", unfreed); }\nunsigned int allocate(unsigned int *tape, unsigned int ptr, unsigned int *taken_cells) {\n    unsigned int requested_mem = tape[ptr];\n    unsigned int consecutive_zero_cells = 0;\n    for (int i=TAPE_SIZE-1; i>0; i--) {\n        if (taken_cells[i] == 0) {\n            consecutive_zero_cells++;\n        } else {\n            consecutive_zero_cells = 0;\n        }\n        if (consecutive_zero_cells >= requested_mem) {\n            unsigned int addr = i;\n            for (int j=0; j<requested_mem; j++) {\n                taken_cells[addr + j] = requested_mem - j;\n            }\n            return addr;\n        }\n    }\n    panic(\"no free memory\");\n}\nvoid free_mem(unsigned int *tape, unsigned int ptr, unsigned int *taken_cells) {\n    unsigned int address = tape[ptr];\n    unsigned int size = taken_cells[address];\n\n    for (int i=0; i<size; i++) {\n        taken_cells[address+i] = 0;\n        tape[address+i] = 0;\n    }\n}\nvoid zero(unsigned int *tape) {\n    for (int i = 0; i < TAPE_SIZE; i++) tape[i] = 0;\n}\nint main() {\n    unsigned int tape[TAPE_SIZE], taken_cells[TAPE_SIZE], ref_stack[256]; \n    unsigned int ptr = 0, ref_ptr = 0;\n    zero(tape);\n    zero(taken_cells);\n");
+fn assemble_lir(code: lir::Program, target: &Target) -> String {
+    let cell = match target.cell_width {
+        CellWidth::Bits8 => "uint8_t",
+        CellWidth::Bits16 => "uint16_t",
+        CellWidth::Bits32 => "uint32_t",
+        CellWidth::Bits64 => "uint64_t",
+    };
+    // How to add `n` to the current cell, and subtract `n` from it
+    let (plus, minus) = match target.overflow {
+        Overflow::Wrap => (
+            "tape[ptr] += n;",
+            "tape[ptr] -= n;"
+        ),
+        Overflow::Saturate => (
+            "tape[ptr] = tape[ptr] > CELL_MAX - n? CELL_MAX : tape[ptr] + n;",
+            "tape[ptr] = tape[ptr] < n? 0 : tape[ptr] - n;"
+        ),
+        Overflow::Trap => (
+            "if (tape[ptr] > CELL_MAX - n) panic(\"overflow\"); tape[ptr] += n;",
+            "if (tape[ptr] < n) panic(\"underflow\"); tape[ptr] -= n;"
+        ),
+    };
+
+    let mut result = format!("#include <stdio.h>\n#include <stdlib.h>\n#include <stdint.h>\n\n#define TAPE_SIZE {tape_size}\n#define CELL_MAX (({cell})-1)\ntypedef {cell} cell;\nvoid panic(char *msg) {{\n    fprintf(stderr, \"panic: %s\\n\", msg);\n    exit(-1);\n}}\nvoid plus(cell *tape, unsigned int ptr, cell n) {{ {plus} }}\nvoid minus(cell *tape, unsigned int ptr, cell n) {{ {minus} }}\nvoid print_tape(cell *tape, unsigned int *taken_cells, unsigned int size) {{ for (unsigned int i = 0; i < size; i++) {{ printf(\"%llu \", (unsigned long long)tape[i]); }} printf(\"\\n\"); int unfreed = 0; for (unsigned int i=0; i < TAPE_SIZE; i++) {{unfreed += taken_cells[i]; i += taken_cells[i];}} printf(\"%d unfreed\\n\", unfreed); }}\ncell allocate(cell *tape, unsigned int ptr, unsigned int *taken_cells) {{\n    unsigned int requested_mem = tape[ptr];\n    if (requested_mem == 0) return 0;\n    unsigned int consecutive_zero_cells = 0;\n    for (int i=TAPE_SIZE-1; i>0; i--) {{\n        if (taken_cells[i] == 0) {{\n            consecutive_zero_cells++;\n        }} else {{\n            consecutive_zero_cells = 0;\n        }}\n        if (consecutive_zero_cells >= requested_mem) {{\n            unsigned int addr = i;\n            for (int j=0; j<requested_mem; j++) {{\n                taken_cells[addr + j] = requested_mem - j;\n            }}\n            return addr;\n        }}\n    }}\n    panic(\"no free memory\");\n}}\nvoid free_mem(cell *tape, unsigned int ptr, unsigned int *taken_cells) {{\n    unsigned int address = tape[ptr];\n    unsigned int size = taken_cells[address];\n\n    for (int i=0; i<size; i++) {{\n        taken_cells[address+i] = 0;\n        tape[address+i] = 0;\n    }}\n}}\ncell reallocate(cell *tape, unsigned int ptr, unsigned int *taken_cells) {{\n    unsigned int address = tape[ptr];\n    unsigned int size = taken_cells[address];\n    cell *contents = malloc(size * sizeof(cell));\n    for (int i=0; i<size; i++) contents[i] = tape[address+i];\n    free_mem(tape, ptr, taken_cells);\n\n    unsigned int requested_mem = tape[ptr+1];\n    tape[ptr] = requested_mem;\n    cell new_address = allocate(tape, ptr, taken_cells);\n    for (int i=0; i<size && i<requested_mem; i++) tape[new_address+i] = contents[i];\n    free(contents);\n    return new_address;\n}}\nvoid zero(cell *tape) {{\n    for (int i = 0; i < TAPE_SIZE; i++) tape[i] = 0;\n}}\nvoid zero_taken(unsigned int *taken_cells) {{\n    for (int i = 0; i < TAPE_SIZE; i++) taken_cells[i] = 0;\n}}\nint main() {{\n    cell tape[TAPE_SIZE]; unsigned int taken_cells[TAPE_SIZE], ref_stack[256]; \n    unsigned int ptr = 0, ref_ptr = 0;\n    long long num = 0;\n    zero(tape);\n    zero_taken(taken_cells);\n",
+        tape_size = target.tape_size(),
+        cell = cell,
+        plus = plus,
+        minus = minus,
+    );
     use lir::Op;
     for op in code.optimize() {
         match op {
-            Op::Plus(n) if n > 0 => result += &format!("tape[ptr]+={};", n),
-            Op::Minus(n) if n > 0 => result += &format!("tape[ptr]-={};", n),
+            Op::Plus(n) if n > 0 => result += &format!("plus(tape, ptr, {});", n),
+            Op::Minus(n) if n > 0 => result += &format!("minus(tape, ptr, {});", n),
             Op::Right(n) if n > 0 => result += &format!("ptr+={};", n),
             Op::Left(n) if n > 0 => result += &format!("ptr-={};", n),
             Op::Loop => result.push_str("while (tape[ptr]) {"),
             Op::End => result.push_str("}"),
-            Op::Get => result.push_str("tape[ptr] = getchar();"),
+            Op::Get => result.push_str("{ int ch = getchar(); tape[ptr] = ch == EOF ? 0 : ch; }"),
             Op::Put => result.push_str("putchar(tape[ptr]);"),
-            Op::Getnum => result.push_str("scanf(\"%d\", &tape[ptr]);"),
-            Op::Putnum => result.push_str("printf(\"%d\", tape[ptr]);"),
+            Op::Getnum => result.push_str("scanf(\"%lld\", &num); tape[ptr] = num;"),
+            Op::Putnum => result.push_str("printf(\"%llu\", (unsigned long long)tape[ptr]);"),
             Op::Deref => result.push_str("ref_stack[ref_ptr++] = ptr; ptr = tape[ptr];"),
             Op::Refer => result.push_str("ptr = ref_stack[--ref_ptr];"),
             Op::Alloc => result.push_str("tape[ptr] = allocate(tape, ptr, taken_cells);"),
             Op::Free => result.push_str("free_mem(tape, ptr, taken_cells);"),
+            Op::Realloc => result.push_str("tape[ptr] = reallocate(tape, ptr, taken_cells);"),
             Op::Comment(comment) => result += &format!("\n// {}\n", comment),
             _ => {}
         }
@@ -89,18 +85,57 @@ fn main() {
             (@arg c: -c "Compile source to C")
             (@arg hir: -h --hir "Compile source to MIR")
             (@arg mir: -m --mir "Compile MIR to Dynamic Brainfuck")
-            (@arg bf: -b --bf "Assemble Dynamic Brainfuck \n(a 32-bit dialect of brainfuck)")
+            (@arg bf: -b --bf "Assemble Dynamic Brainfuck \n(a dialect of brainfuck with configurable cell width)")
         )
+        (@arg run: -r --run "Run the program with the interpreter instead of compiling it")
         (@arg FILE: +required "Input file")
         (@arg OUTPUT: -o +takes_value "Optionally specify output file")
+        (@arg WIDTH: -w --width +takes_value "The number of bits in each cell: 8, 16, 32 (default), or 64")
+        (@arg OVERFLOW: --overflow +takes_value "What cells do on overflow: wrap (default), saturate, or trap")
     )
     .setting(ArgRequiredElseHelp)
     .get_matches();
 
+    let mut target = Target::default();
+    if let Some(width) = matches.value_of("WIDTH") {
+        match width.parse::<CellWidth>() {
+            Ok(width) => target.cell_width = width,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        }
+    }
+    if let Some(overflow) = matches.value_of("OVERFLOW") {
+        match overflow.parse::<Overflow>() {
+            Ok(overflow) => target.overflow = overflow,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        }
+    }
+
 
     if let Some(input_file) = matches.value_of("FILE") {
         // Get the contents of the input file
         if let Ok(contents) = std::fs::read_to_string(input_file) {
+            if matches.is_present("run") {
+                let program = if matches.is_present("mir") {
+                    assemble_mir(contents).map(|s| lir::Program::from(s.as_str())).map_err(|e| e.to_string())
+                } else if matches.is_present("bf") {
+                    Ok(lir::Program::from(contents.as_str()))
+                } else {
+                    compile_hir(input_file, contents, &target).map_err(|e| e.to_string())
+                };
+                let result = program.and_then(|program| program.run(&target, std::io::stdin().lock(), std::io::stdout().lock()));
+                if let Err(e) = result {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+
             let compile_result = if matches.is_present("mir") {
                 match assemble_mir(contents) {
                     Ok(s) => s,
@@ -110,9 +145,9 @@ fn main() {
                     }
                 }
             } else if matches.is_present("bf") {
-                assemble_lir(lir::Program::from(contents.as_str()))
+                assemble_lir(lir::Program::from(contents.as_str()), &target)
             } else if matches.is_present("hir") {
-                match compile_hir(contents) {
+                match compile_hir(input_file, contents, &target) {
                     Ok(s) => s.to_string(),
                     Err(e) => {
                         eprintln!("{}", e);
@@ -120,8 +155,8 @@ fn main() {
                     }
                 }
             } else {
-                match compile_hir(contents) {
-                    Ok(s) => assemble_lir(s),
+                match compile_hir(input_file, contents, &target) {
+                    Ok(s) => assemble_lir(s, &target),
                     Err(e) => {
                         eprintln!("{}", e);
                         return;
@@ -183,7 +218,7 @@ fn main() {
         #5 fact putnum
         putendl
     end
-    "#).unwrap().assemble_with_scope(&scope, &mut program);
+    "#).unwrap().assemble_with_scope(&scope, &mut 0, &mut program);
     */
 
 
@@ -239,7 +274,7 @@ fn main() {
     // asm.assemble(&mut program).unwrap();
     
 //     parse(r#"
-//     "#).unwrap().assemble_with_scope(&scope, &mut program).unwrap();
+//     "#).unwrap().assemble_with_scope(&scope, &mut 0, &mut program).unwrap();
 
 
     // push_str("test!", &mut program);
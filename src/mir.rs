@@ -1,4 +1,4 @@
-use alloc::collections::BTreeMap;
+use alloc::{borrow::Cow, collections::BTreeMap};
 use core::fmt;
 use super::{error, lir::Program};
 
@@ -16,7 +16,7 @@ impl fmt::Display for Address {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct Literal(pub u32);
+pub struct Literal(pub u64);
 
 impl fmt::Display for Literal {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -37,15 +37,51 @@ pub enum Error {
     CannotGetRuntimeAddress(Location),
     
     ParseError(String),
+
+    /// An operation pops more cells than there are on the stack
+    StackUnderflow(Op),
+    /// The body of a loop or conditional changes the size of the stack by some number of cells
+    UnbalancedBlock(&'static str, i64),
+    /// A frame's code leaves a different number of cells than its return size
+    FrameSize(u32, u32),
+    /// An error inside of a block, macro, or frame
+    Within(String, Box<Error>),
+    /// A macro is given a different number of arguments than it has parameters
+    ArgumentCount(String, usize, usize),
+    /// A size is given by a parameter whose argument isn't a number
+    SizeParameter(String),
+    /// Static data is assembled before the program is linked
+    UnlinkedData(String),
+    /// An indirect call has no functions with its signature in scope to call
+    NoCallTarget(String),
+}
+
+impl Error {
+    fn within(context: impl ToString) -> impl FnOnce(Self) -> Self {
+        move |e| Self::Within(context.to_string(), Box::new(e))
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "\x1b[91merror: \x1b[m\x1b[0m")?;
-        match self {
+        let mut error = self;
+        while let Error::Within(context, e) = error {
+            write!(f, "in {}: ", context)?;
+            error = e;
+        }
+        match error {
             Error::MacroNotDefined(name) => write!(f, "macro '{}' not defined", name),
             Error::CannotGetRuntimeAddress(location) => write!(f, "cannot get runtime address of {}", location),
             Error::ParseError(msg) => write!(f, "\n{}", msg),
+            Error::StackUnderflow(op) => write!(f, "{:?} pops more cells than there are on the stack", op),
+            Error::UnbalancedBlock(block, change) => write!(f, "{} body changes the size of the stack by {} cells", block, change),
+            Error::FrameSize(expected, found) => write!(f, "frame should return {} cells, but leaves {}", expected, found),
+            Error::ArgumentCount(name, expected, found) => write!(f, "macro '{}' takes {} arguments, but was given {}", name, expected, found),
+            Error::SizeParameter(name) => write!(f, "parameter '{}' is used as a size, but its argument isn't a number", name),
+            Error::UnlinkedData(label) => write!(f, "static data '{}' must be linked before it's assembled", label),
+            Error::NoCallTarget(signature) => write!(f, "indirect call to '{}', but no functions with that signature are in scope", signature),
+            Error::Within(_, _) => unreachable!(),
         }
     }
 }
@@ -63,23 +99,136 @@ pub fn parse(code: impl ToString) -> Result<Op, Error> {
 }
 
 
-pub fn function(mut args: Vec<(String, u32)>, ret_size: u32, code: Vec<Op>) -> Op {
+/// A variable captured by a function from the scope it was defined in
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Capture {
+    /// A copy of a value with a given size
+    Value(String, u32),
+    /// The address of a variable
+    Reference(String),
+}
+
+/// Create a frame for a function. The caller pushes the arguments,
+/// followed by the environment of captured variables.
+pub fn function(args: Vec<(String, u32)>, env: Vec<Capture>, ret_size: u32, code: Vec<Op>) -> Op {
+    let mut bindings = args.into_iter()
+        .map(|(name, size)| (name, size, false))
+        .collect::<Vec<_>>();
+    for capture in env {
+        bindings.push(match capture {
+            Capture::Value(name, size) => (name, size, false),
+            Capture::Reference(name) => (name, 1, true),
+        });
+    }
+
     let mut offset = 0;
-    let mut args_size = 0;
+    let mut frame_size = 0;
     let mut result = Op::Do(code);
-    for (_, size) in &args {
-        args_size += size;
+    for (_, size, _) in &bindings {
+        frame_size += size;
     }
     
-    while let Some((name, size)) = args.pop() {
+    while let Some((name, size, is_reference)) = bindings.pop() {
         offset += size;
-        result = Op::Let(name, vec![
+        let mut address = vec![
             Op::LoadFrom(FP, 1),
-            Op::Increment(SP.deref(), args_size - offset),
-        ], vec![result]);
+            Op::Increment(SP.deref(), frame_size - offset),
+        ];
+        if is_reference {
+            // The environment stores the address of the variable
+            address.push(Op::Load(1));
+        }
+        result = Op::Let(name, address, vec![result]);
     }
     
-    Op::Frame(args_size, ret_size, vec![result])
+    Op::Frame(frame_size, ret_size, vec![result])
+}
+
+/// A new name for a macro or parameter, which no other name is the same as.
+/// `names` is the number of fresh names that have been made so far.
+fn fresh(name: &str, names: &mut usize) -> String {
+    *names += 1;
+    format!("{}'{}", name, names)
+}
+
+/// The name that a fresh name was made from, for error messages
+fn original_name(name: &str) -> &str {
+    name.split('\'').next().unwrap_or(name)
+}
+
+/// Rename every macro and parameter bound in some code, so that the
+/// arguments substituted into it can't refer to them by accident.
+/// The new names contain a `'`, which identifiers can't.
+fn fresh_names(code: &[Op], names: &mut usize) -> Vec<Op> {
+    code.iter().map(|op| match op {
+        Op::Let(name, val, body) => {
+            let new_name = fresh(name, names);
+            let body = substitute(fresh_names(body, names), name, &[Op::Macro(new_name.clone())]);
+            Op::Let(new_name, fresh_names(val, names), body)
+        }
+        Op::Static(label, data, body) => {
+            let new_label = fresh(label, names);
+            let body = substitute(fresh_names(body, names), label, &[Op::Macro(new_label.clone())]);
+            Op::Static(new_label, data.clone(), body)
+        }
+        Op::Template(params, body) => {
+            let mut body = fresh_names(body, names);
+            let params = params.iter().map(|param| {
+                let new_param = fresh(param, names);
+                body = substitute(core::mem::take(&mut body), param, &[Op::Macro(new_param.clone())]);
+                new_param
+            }).collect();
+            Op::Template(params, body)
+        }
+        Op::Expand(name, args) => Op::Expand(name.clone(), args.iter().map(|arg| fresh_names(arg, names)).collect()),
+        Op::Do(code) => Op::Do(fresh_names(code, names)),
+        Op::Frame(args_size, ret_size, code) => Op::Frame(*args_size, *ret_size, fresh_names(code, names)),
+        Op::While(cond, body) => Op::While(fresh_names(cond, names), fresh_names(body, names)),
+        Op::If(cond, body) => Op::If(fresh_names(cond, names), fresh_names(body, names)),
+        op => op.clone(),
+    }).collect()
+}
+
+/// Replace the uses of a macro or parameter with some code
+fn substitute(code: Vec<Op>, name: &str, with: &[Op]) -> Vec<Op> {
+    let mut result = vec![];
+    for op in code {
+        match op {
+            Op::Macro(m) if m == name => result.extend(with.iter().cloned()),
+            Op::Sized(m, op) if m == name => result.push(match with {
+                [Op::PushLiteral(Literal(n))] => op.with_size(*n as u32),
+                // The parameter is being renamed
+                [Op::Macro(new_name)] => Op::Sized(new_name.clone(), op),
+                _ => Op::Sized(m, op),
+            }),
+            Op::Expand(m, args) => {
+                let args = args.into_iter().map(|arg| substitute(arg, name, with)).collect();
+                result.push(match with {
+                    [Op::Macro(new_name)] if m == name => Op::Expand(new_name.clone(), args),
+                    _ => Op::Expand(m, args),
+                });
+            }
+            Op::Let(m, val, body) => {
+                let val = substitute(val, name, with);
+                // The name is shadowed in the body of a `Let` with the same name
+                let body = if m == name { body } else { substitute(body, name, with) };
+                result.push(Op::Let(m, val, body));
+            }
+            Op::Static(label, data, body) => {
+                let body = if label == name { body } else { substitute(body, name, with) };
+                result.push(Op::Static(label, data, body));
+            }
+            Op::Template(params, body) if !params.iter().any(|param| param == name) => {
+                result.push(Op::Template(params, substitute(body, name, with)));
+            }
+            Op::Do(code) => result.push(Op::Do(substitute(code, name, with))),
+            Op::Frame(args_size, ret_size, code) => result.push(Op::Frame(args_size, ret_size, substitute(code, name, with))),
+            Op::While(cond, body) => result.push(Op::While(substitute(cond, name, with), substitute(body, name, with))),
+            Op::If(cond, body) => result.push(Op::If(substitute(cond, name, with), substitute(body, name, with))),
+            op => result.push(op),
+        }
+    }
+    result
 }
 
 impl Location {
@@ -150,7 +299,7 @@ impl Location {
 
     pub fn plus(&self, n: u32, program: &mut Program) {
         self.to(program);
-        program.plus(n);
+        program.plus(n as u64);
         self.from(program);
     }
 
@@ -162,7 +311,7 @@ impl Location {
 
     pub fn minus(&self, n: u32, program: &mut Program) {
         self.to(program);
-        program.minus(n);
+        program.minus(n as u64);
         self.from(program);
     }
 
@@ -172,9 +321,11 @@ impl Location {
         self.from(program);
     }
 
-    pub fn set(&self, value: u32, program: &mut Program) {
+    pub fn set(&self, value: u64, program: &mut Program) {
         self.zero(program);
-        self.plus(value, program);
+        self.to(program);
+        program.plus(value);
+        self.from(program);
     }
 
     pub fn alloc(&self, program: &mut Program) {
@@ -190,6 +341,14 @@ impl Location {
         self.from(program);
     }
 
+    /// Reallocate the allocation whose address is in this cell, to the
+    /// size in the cell after it
+    pub fn realloc(&self, program: &mut Program) {
+        self.to(program);
+        program.realloc();
+        self.from(program);
+    }
+
     pub fn put(&self, program: &mut Program) {
         self.to(program);
         program.put();
@@ -215,6 +374,21 @@ impl Location {
     }
 
 
+    /// Replace the cell with its logical not
+    pub fn not(&self, program: &mut Program) {
+        TMP0.zero(program);
+        self.begin_loop(program);
+        TMP0.inc(program);
+        self.zero(program);
+        self.end_loop(program);
+        self.inc(program);
+
+        TMP0.begin_loop(program);
+        self.dec(program);
+        TMP0.dec(program);
+        TMP0.end_loop(program);
+    }
+
     pub fn begin_loop(&self, program: &mut Program) {
         self.to(program);
         program.begin_loop();
@@ -302,6 +476,162 @@ impl fmt::Display for Location {
     }
 }
 
+/// Words that can't be used as the names of macros without quotes
+const KEYWORDS: &[&str] = &[
+    "let", "data", "in", "end", "do", "while", "if", "frame", "fn", "alloc", "free", "realloc", "get", "dup", "dump",
+    "slide", "set", "push", "pop", "load", "store", "call", "signed", "putchar", "putnum",
+    "getchar", "getnum", "void", "int", "char", "bool", "true", "false",
+];
+
+/// Write the name of a macro, quoting it if it isn't an identifier
+fn write_name(f: &mut fmt::Formatter, name: &str) -> fmt::Result {
+    let is_identifier = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if is_identifier && !KEYWORDS.contains(&name) {
+        write!(f, "{}", name)
+    } else {
+        write!(f, "`{}`", name)
+    }
+}
+
+/// Write a sequence of operations as a single expression
+fn write_expr(f: &mut fmt::Formatter, code: &[Op]) -> fmt::Result {
+    match code {
+        [op] => write!(f, "{}", op),
+        code => {
+            write!(f, "(")?;
+            write_code(f, code)?;
+            write!(f, ")")
+        }
+    }
+}
+
+/// Write a sequence of operations separated by spaces
+fn write_code(f: &mut fmt::Formatter, code: &[Op]) -> fmt::Result {
+    for (i, op) in code.iter().enumerate() {
+        if i > 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{}", op)?;
+    }
+    Ok(())
+}
+
+/// Operations are written as MIR source code, which can be parsed again
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Let(name, val, body) => {
+                write!(f, "let ")?;
+                write_name(f, name)?;
+                match val.as_slice() {
+                    [Self::Template(params, code)] => {
+                        write!(f, "({}) = ", params.join(", "))?;
+                        write_expr(f, code)?;
+                    }
+                    val => {
+                        write!(f, " = ")?;
+                        write_expr(f, val)?;
+                    }
+                }
+                write!(f, " in ")?;
+                write_code(f, body)?;
+                write!(f, " end")
+            }
+            Self::Macro(name) => write_name(f, name),
+            Self::Static(label, data, body) => {
+                write!(f, "data ")?;
+                write_name(f, label)?;
+                write!(f, " =")?;
+                for cell in data {
+                    write!(f, " {}", cell)?;
+                }
+                write!(f, " in ")?;
+                write_code(f, body)?;
+                write!(f, " end")
+            }
+            // Templates are only written as part of the `Let` that binds them
+            Self::Template(_, _) => write!(f, "()"),
+            Self::Expand(name, args) => {
+                write!(f, "{}!(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_code(f, arg)?;
+                }
+                write!(f, ")")
+            }
+            Self::Sized(param, op) => match **op {
+                Self::Stalloc(_) => write!(f, "get %{}", param),
+                Self::Stfree(_) => write!(f, "dump %{}", param),
+                Self::Store(_) => write!(f, "= %{}", param),
+                Self::Load(_) => write!(f, "@ %{}", param),
+                Self::EqN(_) => write!(f, "== %{}", param),
+                _ => write!(f, "{}", op),
+            },
+            Self::Frame(args_size, ret_size, code) => {
+                write!(f, "frame %{} -> %{} do ", args_size, ret_size)?;
+                write_code(f, code)?;
+                write!(f, " end")
+            }
+            Self::CallIndirect(signature) => write!(f, "call \"{}\"", signature.replace('\"', "\\\"")),
+            Self::Do(code) => {
+                write!(f, "(")?;
+                write_code(f, code)?;
+                write!(f, ")")
+            }
+            Self::Set(n) => write!(f, "set {}", n),
+            Self::StoreAt(loc, n) => write!(f, "store [{}] %{}", loc, n),
+            Self::LoadFrom(loc, n) => write!(f, "load [{}] %{}", loc, n),
+            Self::Store(n) => write!(f, "= %{}", n),
+            Self::Load(n) => write!(f, "@ %{}", n),
+            Self::Alloc => write!(f, "alloc"),
+            Self::Free => write!(f, "free"),
+            Self::Realloc => write!(f, "realloc"),
+            Self::Duplicate => write!(f, "dup"),
+            Self::Putchar => write!(f, "putchar"),
+            Self::Getchar => write!(f, "getchar"),
+            Self::Putnum => write!(f, "putnum"),
+            Self::Getnum => write!(f, "getnum"),
+            Self::While(cond, body) | Self::If(cond, body) => {
+                let keyword = if let Self::While(_, _) = self { "while" } else { "if" };
+                write!(f, "{} ", keyword)?;
+                write_expr(f, cond)?;
+                write!(f, " do ")?;
+                write_code(f, body)?;
+                write!(f, " end")
+            }
+            Self::Increment(loc, n) => write!(f, "[{}] += {}", loc, n),
+            Self::Decrement(loc, n) => write!(f, "[{}] -= {}", loc, n),
+            Self::Not => write!(f, "!"),
+            Self::Or => write!(f, "|"),
+            Self::And => write!(f, "&"),
+            Self::Add => write!(f, "+"),
+            Self::Sub => write!(f, "-"),
+            Self::Mul => write!(f, "*"),
+            Self::Div => write!(f, "/"),
+            Self::Eq => write!(f, "=="),
+            Self::Neq => write!(f, "!="),
+            Self::EqN(n) => write!(f, "== %{}", n),
+            Self::SignedAdd => write!(f, "signed +"),
+            Self::SignedSub => write!(f, "signed -"),
+            Self::SignedMul => write!(f, "signed *"),
+            Self::SignedDiv => write!(f, "signed /"),
+            Self::SignedEq => write!(f, "signed =="),
+            Self::SignedPutnum => write!(f, "signed putnum"),
+            Self::SignedGetnum => write!(f, "signed getnum"),
+            Self::Pop(loc) => write!(f, "pop [{}]", loc),
+            Self::PushLiteral(n) => write!(f, "{}", n),
+            Self::PushAddress(address) => write!(f, "&[{}]", Location::Address(*address)),
+            Self::Push(loc) => write!(f, "push [{}]", loc),
+            Self::Stalloc(n) => write!(f, "get %{}", n),
+            Self::Stfree(n) => write!(f, "dump %{}", n),
+            Self::Slide(keep, drop) => write!(f, "slide %{} %{}", keep, drop),
+        }
+    }
+}
+
 pub const TOTAL_REGISTERS: u32 = 14;
 
 
@@ -316,6 +646,8 @@ pub const TMP0: Location = Location::Address(Address(1));
 pub const TMP1: Location = Location::Address(Address(2));
 pub const TMP2: Location = Location::Address(Address(4));
 pub const TMP3: Location = Location::Address(Address(5));
+/// `TMP3` is followed by `TMP4` and `TMP5`, so it can be branched on with `if_nonzero`
+pub const TMP3_ADDRESS: Address = Address(5);
 pub const TMP4: Location = Location::Address(Address(6));
 pub const TMP5: Location = Location::Address(Address(7));
 
@@ -325,14 +657,27 @@ pub const R1: Location = Location::Address(Address(9));
 pub const R2: Location = Location::Address(Address(10));
 pub const R3: Location = Location::Address(Address(11));
 pub const R4: Location = Location::Address(Address(12));
+/// Holds the function index during `CallIndirect`
 pub const R5: Location = Location::Address(Address(13));
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Op {
     Let(String, Vec<Self>, Vec<Self>),
     Macro(String),
+    /// A macro's parameters and code. It's bound with `Let`, and does nothing on its own.
+    Template(Vec<String>, Vec<Self>),
+    /// Expand a macro, substituting the arguments for its parameters
+    Expand(String, Vec<Vec<Self>>),
+    /// An operation whose size is a macro parameter
+    Sized(String, Box<Self>),
+    /// Cells of data that are placed after the registers before the program
+    /// runs. In the code, the label is a macro that pushes their address.
+    Static(String, Vec<Literal>, Vec<Self>),
 
     Frame(u32, u32, Vec<Self>),
+    /// Pop a function index and call the function with that index.
+    /// The candidates are the macros in scope named `{signature}#{index}`.
+    CallIndirect(String),
     Do(Vec<Self>),
 
 
@@ -354,6 +699,9 @@ pub enum Op {
     Alloc,
     /// Pop an address off the stack, and free that number of cells
     Free,
+    /// Pop a size and an address off the stack, move the allocation at the
+    /// address to a block of that size, and push the block's address
+    Realloc,
     /// Duplicate the top cell on the stack
     Duplicate,
 
@@ -405,6 +753,36 @@ pub enum Op {
     /// Pop two cells and push their inequality
     Neq,
 
+    /// Pop two values of the given size and push their equality. The
+    /// cells are compared in order, and the comparison stops at the
+    /// first pair that differs.
+    EqN(u32),
+
+    /// Pop two signed integers and push their sum.
+    ///
+    /// A signed integer takes two cells: a sign, which is 1 if the
+    /// integer is negative, followed by its magnitude. Zero is always
+    /// stored with a sign of 0, so equal integers have equal cells.
+    SignedAdd,
+
+    /// Pop two signed integers and push their difference
+    SignedSub,
+
+    /// Pop two signed integers and push their product
+    SignedMul,
+
+    /// Pop two signed integers and push their quotient
+    SignedDiv,
+
+    /// Pop two signed integers and push their equality
+    SignedEq,
+
+    /// Pop a signed integer and print it as a number
+    SignedPutnum,
+
+    /// Get a signed number from STDIN and push it
+    SignedGetnum,
+
     /// Pop a value from the stack into an address
     Pop(Location),
 
@@ -419,6 +797,9 @@ pub enum Op {
     Stalloc(u32),
     /// Pop a number of cells off the stack
     Stfree(u32),
+    /// Move the top `keep` cells on the stack down by `drop` cells,
+    /// removing the `drop` cells that were beneath them
+    Slide(u32, u32),
 }
 
 pub fn copy_cell(x: Location, y: Location, program: &mut Program) {
@@ -437,108 +818,439 @@ pub fn copy_cell(x: Location, y: Location, program: &mut Program) {
     TMP0.end_loop(program);
 }
 
+/// Move the value of `y` into `x`, leaving `y` zeroed.
+/// This is cheaper than `copy_cell` when `y` isn't needed anymore.
+pub fn move_cell(x: Location, y: Location, program: &mut Program) {
+    x.zero(program);
+    y.begin_loop(program);
+    x.inc(program);
+    y.dec(program);
+    y.end_loop(program);
+}
+
+/// Run `then` if the cell at `x` is nonzero, and `otherwise` if it is zero.
+/// Unlike a loop over a copy of `x`, this takes constant time no matter
+/// the value of `x`. The two cells after `x` are used as flags, and must
+/// not be touched by either branch.
+pub fn if_nonzero(x: Address, program: &mut Program, then: impl FnOnce(&mut Program), otherwise: impl FnOnce(&mut Program)) {
+    let cell = Location::Address(x);
+    let flag = cell.offset(1);
+    let zero = cell.offset(2);
+    flag.set(1, program);
+    zero.zero(program);
+
+    // If x is nonzero, run `then` and exit the loop on the cleared flag
+    cell.to(program);
+    program.begin_loop();
+    cell.from(program);
+    then(program);
+    flag.to(program);
+    program.minus(1);
+    program.end_loop();
+
+    // Now the pointer is on the flag if x was nonzero, or on x if it was zero.
+    // Step right: the loop is only entered if we land on the set flag.
+    program.right(1);
+    program.begin_loop();
+    program.left(1);
+    cell.from(program);
+    otherwise(program);
+    flag.to(program);
+    program.minus(1);
+    program.right(1);
+    program.end_loop();
+    zero.from(program);
+}
+
+/// Push a signed integer with its sign on the top of the stack,
+/// and its magnitude in TMP3. If the magnitude is zero, the sign
+/// is made positive.
+fn normalize_sign(program: &mut Program) {
+    if_nonzero(TMP3_ADDRESS, program, |_| {}, |program| {
+        SP.deref().zero(program);
+    });
+    TMP3.push(program);
+}
+
+/// Push whether or not the character on the top of the stack is a decimal digit
+fn push_is_digit(program: &mut Program) {
+    let count_down = |n: u64, program: &mut Program| {
+        TMP1.set(n, program);
+        TMP1.begin_loop(program);
+        TMP1.dec(program);
+        if_nonzero(TMP3_ADDRESS, program, |program| {
+            TMP3.dec(program);
+        }, |_| {});
+        TMP1.end_loop(program);
+    };
+
+    copy_cell(TMP3, SP.deref(), program);
+    TMP2.zero(program);
+    // The character is at least '0' if it's still nonzero after counting down to '/'
+    count_down('0' as u64 - 1, program);
+    if_nonzero(TMP3_ADDRESS, program, |program| {
+        TMP2.inc(program);
+    }, |_| {});
+    // The character is at most '9' if it's zero after counting down ten more
+    count_down(10, program);
+    if_nonzero(TMP3_ADDRESS, program, |program| {
+        TMP2.zero(program);
+    }, |_| {});
+    TMP3.zero(program);
+    TMP2.push(program);
+}
+
 impl Op {
     pub fn assemble(&self, program: &mut Program) -> Result<(), Error> {
-        self.assemble_with_scope(&BTreeMap::new(), program)
+        self.assemble_program(&BTreeMap::new(), program)
     }
 
-    pub fn assemble_with_scope(&self, scope: &BTreeMap<String, Vec<Self>>, program: &mut Program) -> Result<(), Error> {
+    /// Assemble a whole program with some predefined macros. Its static
+    /// data is laid out after the registers, and the stack starts after that.
+    pub fn assemble_program(&self, scope: &BTreeMap<String, Vec<Self>>, program: &mut Program) -> Result<(), Error> {
+        let (code, data) = self.link();
+        code.validate_with_scope(scope)?;
+
+        for (i, cell) in data.iter().enumerate() {
+            Location::Address(Address(TOTAL_REGISTERS + i as u32)).set(cell.0, program);
+        }
+        let stack = TOTAL_REGISTERS + data.len() as u32;
+        SP.set(stack as u64, program);
+        FP.set(stack as u64, program);
+
+        code.assemble_with_scope(scope, &mut 0, program)
+    }
+
+    /// Lay out the static data in the program, starting after the registers.
+    /// Each label becomes a macro that pushes the address of its data.
+    pub fn link(&self) -> (Self, Vec<Literal>) {
+        let mut data = vec![];
+        let code = self.link_with(&mut data);
+        (code, data)
+    }
+
+    fn link_with(&self, data: &mut Vec<Literal>) -> Self {
+        let link = |code: &[Self], data: &mut Vec<Literal>| code.iter().map(|op| op.link_with(data)).collect();
         match self {
-            Self::Do(code) => {
-                for op in code {
-                    op.assemble_with_scope(&scope, program)?;
+            Self::Static(label, cells, body) => {
+                let address = TOTAL_REGISTERS + data.len() as u32;
+                data.extend(cells);
+                Self::Let(label.clone(), vec![Self::PushLiteral(Literal(address as u64))], link(body, data))
+            }
+            Self::Let(name, val, body) => Self::Let(name.clone(), link(val, data), link(body, data)),
+            Self::Template(params, body) => Self::Template(params.clone(), link(body, data)),
+            Self::Expand(name, args) => Self::Expand(name.clone(), args.iter().map(|arg| link(arg, data)).collect()),
+            Self::Do(code) => Self::Do(link(code, data)),
+            Self::Frame(args_size, ret_size, code) => Self::Frame(*args_size, *ret_size, link(code, data)),
+            Self::While(cond, body) => Self::While(link(cond, data), link(body, data)),
+            Self::If(cond, body) => Self::If(link(cond, data), link(body, data)),
+            op => op.clone(),
+        }
+    }
+
+    /// Check that the program never pops more cells than there are on the
+    /// stack, that loops and conditionals leave the stack the size they found
+    /// it, and that frames leave exactly their return value.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.validate_with_scope(&BTreeMap::new())
+    }
+
+    pub fn validate_with_scope(&self, scope: &BTreeMap<String, Vec<Self>>) -> Result<(), Error> {
+        self.stack_depth(scope, &mut 0, 0)?;
+        Ok(())
+    }
+
+    /// The code a `Macro` or `Expand` expands to. The arguments of an
+    /// `Expand` are substituted for the macro's parameters.
+    fn expand<'a>(&self, scope: &'a BTreeMap<String, Vec<Self>>, names: &mut usize) -> Result<Cow<'a, [Self]>, Error> {
+        let (name, args) = match self {
+            Self::Macro(name) => (name, &[][..]),
+            Self::Expand(name, args) => (name, args.as_slice()),
+            _ => unreachable!("only macros are expanded"),
+        };
+        let code = scope.get(name).ok_or_else(|| Error::MacroNotDefined(name.clone()))?;
+        match code.as_slice() {
+            [Self::Template(params, body)] => {
+                if params.len() != args.len() {
+                    return Err(Error::ArgumentCount(name.clone(), params.len(), args.len()));
+                }
+                // Rename all of the parameters before substituting any of them,
+                // so that an argument can't be captured by a later parameter
+                let mut body = fresh_names(body, names);
+                let fresh_params = params.iter().map(|param| fresh(param, names)).collect::<Vec<_>>();
+                for (param, new_param) in params.iter().zip(&fresh_params) {
+                    body = substitute(body, param, &[Self::Macro(new_param.clone())]);
+                }
+                for (param, arg) in fresh_params.iter().zip(args) {
+                    body = substitute(body, param, arg);
                 }
+                Ok(Cow::Owned(body))
             }
-            Self::Let(name, val, ret) => {
+            code if args.is_empty() => Ok(Cow::Borrowed(code)),
+            _ => Err(Error::ArgumentCount(name.clone(), 0, args.len())),
+        }
+    }
+
+    /// This operation with a size given by a macro parameter
+    fn with_size(self, n: u32) -> Self {
+        match self {
+            Self::Stalloc(_) => Self::Stalloc(n),
+            Self::Stfree(_) => Self::Stfree(n),
+            Self::Store(_) => Self::Store(n),
+            Self::Load(_) => Self::Load(n),
+            Self::EqN(_) => Self::EqN(n),
+            op => op,
+        }
+    }
+
+    /// The number of cells this operation pops and pushes, if it
+    /// doesn't contain any other operations
+    fn stack_effect(&self) -> Option<(u32, u32)> {
+        Some(match self {
+            Self::PushLiteral(_) | Self::PushAddress(_) | Self::Push(_)
+            | Self::Getchar | Self::Getnum => (0, 1),
+            Self::Pop(_) | Self::Set(_) | Self::Free | Self::Putchar | Self::Putnum => (1, 0),
+            Self::LoadFrom(_, n) | Self::Stalloc(n) => (0, *n),
+            Self::StoreAt(_, n) | Self::Stfree(n) => (*n, 0),
+            Self::Store(n) => (n + 1, 0),
+            Self::Load(n) => (1, *n),
+            Self::Alloc | Self::Not => (1, 1),
+            Self::Duplicate => (1, 2),
+            Self::Increment(_, _) | Self::Decrement(_, _) => (0, 0),
+            Self::Add | Self::Sub | Self::Mul | Self::Div
+            | Self::Eq | Self::Neq | Self::And | Self::Or | Self::Realloc => (2, 1),
+            Self::SignedAdd | Self::SignedSub | Self::SignedMul | Self::SignedDiv => (4, 2),
+            Self::SignedEq => (4, 1),
+            Self::EqN(n) => (2 * n, 1),
+            Self::SignedPutnum => (2, 0),
+            Self::SignedGetnum => (0, 2),
+            Self::Slide(keep, drop) => (keep + drop, *keep),
+            _ => return None,
+        })
+    }
+
+    fn sequence_depth(code: &[Self], scope: &BTreeMap<String, Vec<Self>>, names: &mut usize, mut depth: u32) -> Result<u32, Error> {
+        for op in code {
+            depth = op.stack_depth(scope, names, depth)?;
+        }
+        Ok(depth)
+    }
+
+    /// The number of cells on the stack after this operation runs,
+    /// given the number of cells before it. Macros are expanded the
+    /// same way the assembler expands them.
+    fn stack_depth(&self, scope: &BTreeMap<String, Vec<Self>>, names: &mut usize, depth: u32) -> Result<u32, Error> {
+        if let Some((pops, pushes)) = self.stack_effect() {
+            return if pops > depth {
+                Err(Error::StackUnderflow(self.clone()))
+            } else {
+                Ok(depth - pops + pushes)
+            };
+        }
+
+        Ok(match self {
+            Self::Do(code) => Self::sequence_depth(code, scope, names, depth)?,
+            Self::Let(name, val, body) => {
                 let mut new_scope = scope.clone();
                 new_scope.insert(name.clone(), val.clone());
-                
-                for op in ret {
-                    op.assemble_with_scope(&new_scope, program)?;
+                Self::sequence_depth(body, &new_scope, names, depth)?
+            }
+            Self::Macro(name) | Self::Expand(name, _) => {
+                let code = self.expand(scope, names)?;
+                let mut new_scope = scope.clone();
+                new_scope.remove(name);
+                Self::sequence_depth(&code, &new_scope, names, depth).map_err(Error::within(format!("macro '{}'", name)))?
+            }
+            Self::Template(_, _) => depth,
+            Self::Sized(param, _) => return Err(Error::SizeParameter(original_name(param).to_string())),
+            Self::Static(label, _, body) => {
+                // The address isn't known until the program is linked
+                let mut new_scope = scope.clone();
+                new_scope.insert(label.clone(), vec![Self::PushLiteral(Literal(0))]);
+                Self::sequence_depth(body, &new_scope, names, depth)?
+            }
+            Self::Frame(args_size, ret_size, code) => {
+                if *args_size > depth {
+                    return Err(Error::StackUnderflow(Self::Frame(*args_size, *ret_size, vec![])));
                 }
+                // The code can only reach the arguments through the frame pointer
+                let returned = Self::sequence_depth(code, scope, names, 0).map_err(Error::within("frame"))?;
+                if returned != *ret_size {
+                    return Err(Error::FrameSize(*ret_size, returned));
+                }
+                depth - args_size + ret_size
             }
-
-            Self::Macro(name) => {
-                if let Some(code) = scope.get(name) {
-                    let mut new_scope = scope.clone();
-                    new_scope.remove(name);
-                    for op in code {
-                        op.assemble_with_scope(&new_scope, program)?;
+            Self::CallIndirect(signature) => {
+                if depth == 0 {
+                    return Err(Error::StackUnderflow(self.clone()));
+                }
+                // Only one of the candidates runs, so they must all agree
+                let prefix = format!("{}#", signature);
+                let mut result = None;
+                for name in scope.keys().filter(|name| name.starts_with(&prefix)) {
+                    let after = Self::Macro(name.clone()).stack_depth(scope, names, depth - 1)?;
+                    if result.is_some_and(|result| result != after) {
+                        let e = Error::UnbalancedBlock("indirect call", after as i64 - result.unwrap() as i64);
+                        return Err(Error::within(format!("macro '{}'", name))(e));
                     }
-                } else {
-                    return Err(Error::MacroNotDefined(name.clone()));
+                    result = Some(after);
                 }
+                result.ok_or_else(|| Error::NoCallTarget(signature.clone()))?
             }
-
-            Self::Frame(args_size, ret_size, code) => {
-                // Allocate some space on the heap and temporarily spill the arguments there
-
-                if *args_size > 0 {
-                    Self::PushLiteral(Literal(*args_size)).assemble_with_scope(scope, program)?;
-                    Self::Alloc.assemble_with_scope(scope, program)?;
-                    Self::Duplicate.assemble_with_scope(scope, program)?;
-                    TMP5.pop_into(program);
-                    Self::Store(*args_size).assemble_with_scope(scope, program)?;
+            Self::While(cond, body) | Self::If(cond, body) => {
+                let block = if let Self::While(_, _) = self { "while" } else { "if" };
+                let after_cond = Self::sequence_depth(cond, scope, names, depth)
+                    .map_err(Error::within(format!("{} condition", block)))?;
+                if after_cond == 0 {
+                    // Leave out the code, since the condition is what's at fault
+                    let op = if block == "while" { Self::While(vec![], vec![]) } else { Self::If(vec![], vec![]) };
+                    return Err(Error::within(format!("{} condition", block))(Error::StackUnderflow(op)));
+                }
+                let after_body = Self::sequence_depth(body, scope, names, after_cond - 1)
+                    .map_err(Error::within(format!("{} body", block)))?;
+                // A loop runs its condition again after the body, so the body has to
+                // leave the stack how the condition found it. A conditional has to
+                // leave the stack the same whether or not its body runs.
+                let expected = if block == "while" { depth } else { after_cond - 1 };
+                if after_body != expected {
+                    return Err(Error::UnbalancedBlock(block, after_body as i64 - expected as i64));
                 }
+                after_cond - 1
+            }
+            _ => unreachable!("operations without a stack effect are handled above"),
+        })
+    }
 
+    /// Assemble a sequence of operations, using fixed addresses
+    /// directly wherever `direct_access` can
+    fn assemble_sequence(code: &[Self], scope: &BTreeMap<String, Vec<Self>>, names: &mut usize, program: &mut Program) -> Result<(), Error> {
+        let mut i = 0;
+        while i < code.len() {
+            if let Some((op, len)) = Self::direct_access(&code[i..], scope) {
+                op.assemble_with_scope(scope, names, program)?;
+                i += len;
+            } else {
+                code[i].assemble_with_scope(scope, names, program)?;
+                i += 1;
+            }
+        }
+        Ok(())
+    }
 
-                // push old frame pointer
-                FP.push(program);
-                copy_cell(
-                    FP,
-                    SP,
-                    program
-                );
-                // Increment it so it points to the first argument
-                FP.inc(program);
-
-                if *args_size > 0 {
-                    // Load the arguments from the heap
-                    TMP5.push(program);
-                    Self::Load(*args_size).assemble_with_scope(scope, program)?;
-                    // Free the memory we allocated to store them temporarily
-                    TMP5.push(program);
-                    Self::Free.assemble_with_scope(scope, program)?;
+    /// When a macro that pushes a fixed address, like the label of static
+    /// data, is followed by a load, store, increment, or decrement of that
+    /// address, the address can be used directly instead of being pushed
+    /// and followed. Returns the direct operation, and the number of
+    /// operations at the start of `code` that it replaces.
+    fn direct_access(code: &[Self], scope: &BTreeMap<String, Vec<Self>>) -> Option<(Self, usize)> {
+        let location = match code {
+            [Self::Macro(name), ..] => match scope.get(name).map(Vec::as_slice) {
+                Some([Self::PushLiteral(Literal(address))]) => Location::Address(Address(*address as u32)),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        match &code[1..] {
+            [Self::Load(n), ..] => Some((Self::LoadFrom(location, *n), 2)),
+            [Self::Store(n), ..] => Some((Self::StoreAt(location, *n), 2)),
+            [Self::Increment(loc, n), Self::Stfree(1), ..] if *loc == SP.deref().deref() => Some((Self::Increment(location, *n), 3)),
+            [Self::Decrement(loc, n), Self::Stfree(1), ..] if *loc == SP.deref().deref() => Some((Self::Decrement(location, *n), 3)),
+            _ => None,
+        }
+    }
+
+    /// Assemble this operation with some macros in scope. `names` is the
+    /// number of fresh names given to the names bound in expanded macros.
+    pub fn assemble_with_scope(&self, scope: &BTreeMap<String, Vec<Self>>, names: &mut usize, program: &mut Program) -> Result<(), Error> {
+        match self {
+            Self::Do(code) => {
+                Self::assemble_sequence(code, scope, names, program)?;
+            }
+            Self::Let(name, val, ret) => {
+                let mut new_scope = scope.clone();
+                new_scope.insert(name.clone(), val.clone());
+                
+                Self::assemble_sequence(ret, &new_scope, names, program)?;
+            }
+
+            Self::Macro(name) | Self::Expand(name, _) => {
+                let code = self.expand(scope, names)?;
+                let mut new_scope = scope.clone();
+                new_scope.remove(name);
+                Self::assemble_sequence(&code, &new_scope, names, program)?;
+            }
+            Self::Template(_, _) => {}
+            Self::Sized(param, _) => return Err(Error::SizeParameter(original_name(param).to_string())),
+            Self::Static(label, _, _) => return Err(Error::UnlinkedData(label.clone())),
+
+            Self::CallIndirect(signature) => {
+                // Hold the index in a register while we look for the function.
+                // It's cleared before the function runs, so no other candidate matches.
+                R5.pop_into(program);
+                let prefix = format!("{}#", signature);
+                let candidates = scope.keys()
+                    .filter_map(|name| Some((name, name.strip_prefix(&prefix)?.parse::<u32>().ok()?)))
+                    .collect::<Vec<_>>();
+                if candidates.is_empty() {
+                    return Err(Error::NoCallTarget(signature.clone()));
                 }
-                // Run the code in the new frame
-                for op in code {
-                    op.assemble_with_scope(scope, program)?;
+                for (name, index) in candidates {
+                    Self::Do(vec![
+                        Self::Push(R5),
+                        Self::PushLiteral(Literal(index as u64)),
+                        Self::Eq,
+                        Self::If(vec![], vec![
+                            Self::PushLiteral(Literal(0)),
+                            Self::Pop(R5),
+                            Self::Macro(name.clone()),
+                        ]),
+                    ]).assemble_with_scope(scope, names, program)?;
                 }
+                R5.zero(program);
+            }
 
-                if *ret_size > 0 {
-                    // Spill the return value to some memory on the heap
-                    Self::PushLiteral(Literal(*ret_size)).assemble_with_scope(scope, program)?;
-                    Self::Alloc.assemble_with_scope(scope, program)?;
-                    Self::Duplicate.assemble_with_scope(scope, program)?;
-                    TMP5.pop_into(program);
-                    Self::Store(*ret_size).assemble_with_scope(scope, program)?;
-                }
-                if *args_size > 0 {
-                    // Remove the arguments from the stack
-                    Self::Stfree(*args_size).assemble_with_scope(scope, program)?;
+            Self::Frame(args_size, ret_size, code) => {
+                let args = *args_size as i32;
+                // Push the old frame pointer, and rotate it beneath the arguments
+                FP.push(program);
+                move_cell(TMP5, SP.deref(), program);
+                for i in 0..args {
+                    move_cell(SP.deref().offset(-i), SP.deref().offset(-i - 1), program);
                 }
-                // Restore the frame pointer
-                FP.pop_into(program);
-
-                if *ret_size > 0 {
-                    // Load the return value from the heap, and free the memory
-                    TMP5.push(program);
-                    Self::Load(*ret_size).assemble_with_scope(scope, program)?;
-                    TMP5.push(program);
-                    Self::Free.assemble_with_scope(scope, program)?;
+                move_cell(SP.deref().offset(-args), TMP5, program);
+
+                // Point the frame pointer at the first argument
+                copy_cell(FP, SP, program);
+                if args > 0 {
+                    FP.minus(*args_size - 1, program);
+                } else {
+                    FP.inc(program);
                 }
-                TMP5.zero(program);
+
+                // Run the code in the new frame
+                Self::assemble_sequence(code, scope, names, program)?;
+
+                // Remove the arguments from beneath the return value
+                Self::Slide(*ret_size, *args_size).assemble_with_scope(scope, names, program)?;
+                // Restore the frame pointer, and remove it from beneath the return value
+                move_cell(FP, SP.deref().offset(-(*ret_size as i32)), program);
+                Self::Slide(*ret_size, 1).assemble_with_scope(scope, names, program)?;
             }
 
             Self::Pop(loc) => {
                 loc.pop_into(program);
             },
 
-            Self::PushLiteral(Literal(n)) | Self::PushAddress(Address(n)) => {
+            Self::PushLiteral(Literal(n)) => {
                 SP.inc(program);
                 SP.deref().set(*n, program);
             },
 
+            Self::PushAddress(Address(n)) => {
+                SP.inc(program);
+                SP.deref().set(*n as u64, program);
+            },
+
             Self::Push(loc) => {
                 loc.push(program);
             },
@@ -581,6 +1293,17 @@ impl Op {
                 }
             }
 
+            Self::Slide(keep, drop) => {
+                if *drop > 0 {
+                    // Move the bottom cell first, so no cell is overwritten before it's moved
+                    for i in 0..*keep {
+                        let from = SP.deref().offset(i as i32 + 1 - *keep as i32);
+                        move_cell(from.offset(-(*drop as i32)), from, program);
+                    }
+                    SP.minus(*drop, program);
+                }
+            }
+
             Self::Alloc => {
                 SP.deref().alloc(program);
             }
@@ -590,6 +1313,12 @@ impl Op {
                 SP.dec(program);
             }
 
+            Self::Realloc => {
+                SP.deref().offset(-1).realloc(program);
+                SP.deref().zero(program);
+                SP.dec(program);
+            }
+
             Self::Duplicate => {
                 copy_cell(SP.deref().offset(1), SP.deref(), program);
                 SP.inc(program);
@@ -604,19 +1333,7 @@ impl Op {
             }
 
             Self::Not => {
-                let x = SP.deref();
-
-                TMP0.zero(program);
-                x.begin_loop(program);
-                TMP0.inc(program);
-                x.zero(program);
-                x.end_loop(program);
-                x.inc(program);
-
-                TMP0.begin_loop(program);
-                x.dec(program);
-                TMP0.dec(program);
-                TMP0.end_loop(program);
+                SP.deref().not(program);
             }
 
             Self::Add | Self::Or => {
@@ -694,59 +1411,55 @@ impl Op {
                 let x = SP.deref().offset(-1);
                 let y = SP.deref();
 
-                // do division
-                TMP0.zero(program);
+                // Move x into TMP3, and repeatedly subtract y from it,
+                // counting each complete subtraction into x. Like `Eq`,
+                // this never decrements a cell below zero, so it works
+                // regardless of the target's overflow behavior.
+                TMP0.set(1, program);
                 TMP1.zero(program);
                 TMP2.zero(program);
                 TMP3.zero(program);
-
                 x.begin_loop(program);
-                TMP0.inc(program);
+                TMP3.inc(program);
                 x.dec(program);
                 x.end_loop(program);
 
                 TMP0.begin_loop(program);
 
+                // copy y into TMP1
                 y.begin_loop(program);
                 TMP1.inc(program);
                 TMP2.inc(program);
                 y.dec(program);
                 y.end_loop(program);
-
                 TMP2.begin_loop(program);
                 y.inc(program);
                 TMP2.dec(program);
                 TMP2.end_loop(program);
 
-                TMP1.begin_loop(program);
-
+                // subtract y from TMP3, clearing TMP2 if it runs out first
                 TMP2.inc(program);
-                TMP0.dec(program);
-                TMP0.begin_loop(program);
-                TMP2.zero(program);
-                TMP3.inc(program);
-                TMP0.dec(program);
-                TMP0.end_loop(program);
-                
-                TMP3.begin_loop(program);
-                TMP0.inc(program);
-                TMP3.dec(program);
-                TMP3.end_loop(program);
-
-                TMP2.begin_loop(program);
-                TMP1.dec(program);
                 TMP1.begin_loop(program);
-                x.dec(program);
-                TMP1.zero(program);
+                TMP1.dec(program);
+                if_nonzero(TMP3_ADDRESS, program, |program| {
+                    TMP3.dec(program);
+                }, |program| {
+                    TMP2.zero(program);
+                    TMP1.zero(program);
+                });
                 TMP1.end_loop(program);
-                TMP1.inc(program);
 
-                TMP2.dec(program);
+                // keep going only after a complete subtraction
+                // that left something over
+                TMP0.zero(program);
+                TMP2.begin_loop(program);
+                x.inc(program);
+                if_nonzero(TMP3_ADDRESS, program, |program| {
+                    TMP0.inc(program);
+                }, |_| {});
+                TMP2.zero(program);
                 TMP2.end_loop(program);
-                TMP1.dec(program);
 
-                TMP1.end_loop(program);
-                x.inc(program);
                 TMP0.end_loop(program);
 
                 SP.dec(program);
@@ -754,30 +1467,20 @@ impl Op {
 
             Self::If(cond, body) => {
                 let x = TMP2;
-                for op in cond {
-                    op.assemble_with_scope(scope, program)?;
-                }
+                Self::assemble_sequence(cond, scope, names, program)?;
                 x.pop_into(program);
                 x.begin_loop(program);
-                for op in body {
-                    op.assemble_with_scope(scope, program)?;
-                }
+                Self::assemble_sequence(body, scope, names, program)?;
                 x.zero(program);
                 x.end_loop(program);
             }
 
             Self::While(cond, body) => {
-                for op in cond {
-                    op.assemble_with_scope(scope, program)?;
-                }
+                Self::assemble_sequence(cond, scope, names, program)?;
                 SP.deref().begin_loop(program);
                 SP.dec(program);
-                for op in body {
-                    op.assemble_with_scope(scope, program)?;
-                }
-                for op in cond {
-                    op.assemble_with_scope(scope, program)?;
-                }
+                Self::assemble_sequence(body, scope, names, program)?;
+                Self::assemble_sequence(cond, scope, names, program)?;
                 SP.deref().end_loop(program);
                 SP.dec(program);
             }
@@ -785,44 +1488,217 @@ impl Op {
             Self::Eq => {
                 let x = SP.deref().offset(-1);
                 let y = SP.deref();
-                // let x = TMP2;
-                // let y = TMP3;
-                // y.pop_into(program);
-                // x.pop_into(program);
 
-                TMP0.zero(program);
+                // Move x into TMP3, and count y down against it.
+                // This never decrements a cell below zero, so it
+                // works regardless of the target's overflow behavior.
                 TMP1.zero(program);
-
+                TMP3.zero(program);
                 x.begin_loop(program);
-                TMP1.inc(program);
+                TMP3.inc(program);
                 x.dec(program);
                 x.end_loop(program);
-                x.inc(program);
 
                 y.begin_loop(program);
-                TMP1.dec(program);
-                TMP0.inc(program);
                 y.dec(program);
+                if_nonzero(TMP3_ADDRESS, program, |program| {
+                    TMP3.dec(program);
+                }, |program| {
+                    // y is greater than x
+                    TMP1.set(1, program);
+                });
                 y.end_loop(program);
 
-                TMP0.begin_loop(program);
-                y.inc(program);
-                TMP0.dec(program);
-                TMP0.end_loop(program);
-
+                // x and y are equal if neither has anything left over
+                x.inc(program);
+                TMP3.begin_loop(program);
+                x.zero(program);
+                TMP3.zero(program);
+                TMP3.end_loop(program);
                 TMP1.begin_loop(program);
-                x.dec(program);
+                x.zero(program);
                 TMP1.zero(program);
                 TMP1.end_loop(program);
 
                 SP.dec(program);
-                // x.push(program);
             }
             
+            Self::EqN(1) => Self::Eq.assemble_with_scope(scope, names, program)?,
+
+            Self::EqN(n) => {
+                // Keep a result above both values, and compare each pair
+                // of cells only while everything before them was equal
+                let mut code = vec![Self::PushLiteral(Literal(1))];
+                for i in 0..*n {
+                    code.push(Self::If(vec![Self::Duplicate], vec![
+                        Self::Push(SP.deref().offset(-((2 * n - i) as i32) - 1)),
+                        Self::Push(SP.deref().offset(-((n - i) as i32) - 2)),
+                        Self::Eq,
+                        Self::Slide(1, 1),
+                    ]));
+                }
+                code.push(Self::Slide(1, 2 * n));
+                Self::Do(code).assemble_with_scope(scope, names, program)?;
+            }
+
             Self::Neq => {
-                Self::Eq.assemble_with_scope(scope, program)?;
-                Self::Not.assemble_with_scope(scope, program)?;
-                // Self::Sub.assemble_with_scope(scope, program)?;
+                Self::Eq.assemble_with_scope(scope, names, program)?;
+                Self::Not.assemble_with_scope(scope, names, program)?;
+                // Self::Sub.assemble_with_scope(scope, names, program)?;
+            }
+
+            Self::SignedAdd => {
+                // Find out if the signs differ before taking the integers apart
+                Self::Do(vec![
+                    Self::Push(SP.deref().offset(-4)),
+                    Self::Push(SP.deref().offset(-3)),
+                    Self::Neq,
+                ]).assemble_with_scope(scope, names, program)?;
+                TMP2.pop_into(program);
+                TMP1.pop_into(program);
+                // The sign of the right hand side isn't needed anymore
+                SP.dec(program);
+                TMP3.pop_into(program);
+
+                // Now the left sign is on top of the stack, the left magnitude is in TMP3,
+                // and the right magnitude is in TMP1.
+                TMP2.begin_loop(program);
+                TMP1.begin_loop(program);
+                TMP1.dec(program);
+                if_nonzero(TMP3_ADDRESS, program, |program| {
+                    TMP3.dec(program);
+                }, |program| {
+                    // The left side crossed zero, so it takes the sign of the right side,
+                    // and the rest of the right side is added to it.
+                    SP.deref().not(program);
+                    TMP3.inc(program);
+                    TMP1.begin_loop(program);
+                    TMP3.inc(program);
+                    TMP1.dec(program);
+                    TMP1.end_loop(program);
+                });
+                TMP1.end_loop(program);
+                TMP2.zero(program);
+                TMP2.end_loop(program);
+
+                // If the signs are the same, just add the magnitudes
+                TMP1.begin_loop(program);
+                TMP3.inc(program);
+                TMP1.dec(program);
+                TMP1.end_loop(program);
+
+                normalize_sign(program);
+            }
+
+            Self::SignedSub => {
+                // Flip the sign of the right side and add.
+                // Negative zero is normalized by the addition.
+                SP.deref().offset(-1).not(program);
+                Self::SignedAdd.assemble_with_scope(scope, names, program)?;
+            }
+
+            Self::SignedMul | Self::SignedDiv => {
+                Self::Do(vec![
+                    // Combine the magnitudes
+                    Self::Push(SP.deref().offset(-3)),
+                    Self::Push(SP.deref().offset(-2)),
+                    if self == &Self::SignedMul { Self::Mul } else { Self::Div },
+                    // The result is negative if exactly one side is negative
+                    Self::Push(SP.deref().offset(-5)),
+                    Self::Push(SP.deref().offset(-4)),
+                    Self::Neq,
+                ]).assemble_with_scope(scope, names, program)?;
+
+                copy_cell(SP.deref().offset(-5), SP.deref(), program);
+                copy_cell(SP.deref().offset(-4), SP.deref().offset(-1), program);
+                SP.minus(4, program);
+                TMP3.pop_into(program);
+                normalize_sign(program);
+            }
+
+            Self::SignedEq => {
+                Self::Do(vec![
+                    Self::Push(SP.deref().offset(-3)),
+                    Self::Push(SP.deref().offset(-2)),
+                    Self::Eq,
+                    Self::Push(SP.deref().offset(-5)),
+                    Self::Push(SP.deref().offset(-4)),
+                    Self::Eq,
+                    Self::And,
+                ]).assemble_with_scope(scope, names, program)?;
+
+                copy_cell(SP.deref().offset(-4), SP.deref(), program);
+                SP.minus(4, program);
+            }
+
+            Self::SignedPutnum => {
+                TMP3.pop_into(program);
+                Self::If(vec![], vec![
+                    Self::PushLiteral(Literal('-' as u64)),
+                    Self::Putchar,
+                ]).assemble_with_scope(scope, names, program)?;
+                TMP3.push(program);
+                Self::Putnum.assemble_with_scope(scope, names, program)?;
+            }
+
+            Self::SignedGetnum => {
+                // Push the sign, the magnitude, and the first character
+                Self::Do(vec![
+                    Self::PushLiteral(Literal(0)),
+                    Self::PushLiteral(Literal(0)),
+                    Self::Getchar,
+                ]).assemble_with_scope(scope, names, program)?;
+
+                // Skip leading whitespace
+                let mut is_whitespace = vec![Self::PushLiteral(Literal(0))];
+                for ch in &[' ', '\n', '\t', '\r'] {
+                    is_whitespace.extend(vec![
+                        Self::Push(SP.deref().offset(-2)),
+                        Self::PushLiteral(Literal(*ch as u64)),
+                        Self::Eq,
+                        Self::Add,
+                    ]);
+                }
+                Self::While(is_whitespace, vec![
+                    Self::Stfree(1),
+                    Self::Getchar,
+                ]).assemble_with_scope(scope, names, program)?;
+
+                // Read the minus sign
+                Self::Do(vec![
+                    Self::Push(SP.deref().offset(-1)),
+                    Self::PushLiteral(Literal('-' as u64)),
+                    Self::Eq,
+                    Self::If(vec![], vec![
+                        Self::Increment(SP.deref().offset(-2), 1),
+                        Self::Stfree(1),
+                        Self::Getchar,
+                    ])
+                ]).assemble_with_scope(scope, names, program)?;
+
+                // Read the digits
+                push_is_digit(program);
+                SP.deref().begin_loop(program);
+                SP.dec(program);
+                Self::Do(vec![
+                    Self::PushLiteral(Literal('0' as u64)),
+                    Self::Sub,
+                    Self::Push(SP.deref().offset(-2)),
+                    Self::PushLiteral(Literal(10)),
+                    Self::Mul,
+                    Self::Add,
+                ]).assemble_with_scope(scope, names, program)?;
+                copy_cell(SP.deref().offset(-1), SP.deref(), program);
+                SP.dec(program);
+                Self::Getchar.assemble_with_scope(scope, names, program)?;
+                push_is_digit(program);
+                SP.deref().end_loop(program);
+                SP.dec(program);
+
+                // Throw away the character after the number
+                SP.dec(program);
+                TMP3.pop_into(program);
+                normalize_sign(program);
             }
 
             Self::Putnum => {
@@ -849,7 +1725,10 @@ impl Op {
                 SP.deref().get(program);
             }
 
-            _ => unimplemented!()
+            Self::Set(Literal(n)) => {
+                TMP2.pop_into(program);
+                TMP2.deref().set(*n, program);
+            }
         }
         Ok(())
     }
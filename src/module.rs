@@ -0,0 +1,234 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+use super::hir::{self, Error, Expr, Import, Item};
+
+/// The functions, constants, and globals a loaded file defines, by
+/// their name in the file and the name they're defined with in the program
+type Exports = BTreeMap<String, String>;
+
+/// The standard library, which is embedded in the compiler.
+/// Its files are imported with paths like `"std/str.hb"`.
+const STD: &[(&str, &str)] = &[
+    ("str.hb", include_str!("../std/str.hb")),
+    ("num.hb", include_str!("../std/num.hb")),
+    ("mem.hb", include_str!("../std/mem.hb")),
+    ("io.hb", include_str!("../std/io.hb")),
+];
+
+/// Functions that are built into the language. Importing one of them
+/// by name from any file does nothing, so older programs still compile.
+const BUILTINS: &[&str] = &["memcpy", "memset", "realloc", "sizeof"];
+
+/// Loads a file along with everything it imports, and
+/// combines them into a single expression.
+#[derive(Default)]
+pub struct Loader {
+    /// The files that have already been loaded, by their canonical path
+    loaded: BTreeMap<PathBuf, Exports>,
+    /// The files that are being loaded, to detect import cycles
+    loading: Vec<PathBuf>,
+    /// The namespaces that imported functions have been defined in
+    namespaces: BTreeSet<String>,
+    /// Every definition in the program, in the order they're written
+    definitions: Vec<Item>,
+}
+
+/// Load a program from its source code and path, and the files it imports
+pub fn load(path: impl AsRef<Path>, code: String) -> Result<Expr, Error> {
+    Loader::default().load(path.as_ref(), code)
+}
+
+impl Loader {
+    pub fn load(mut self, path: &Path, code: String) -> Result<Expr, Error> {
+        let module = parse(path, code)?;
+        // The main file's code is given directly, so it doesn't have to exist
+        let canonical = canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        self.loading.push(canonical.clone());
+        // The main file's functions keep their names
+        let body = self.define(&canonical, module, None)?;
+
+        // Without an expression to run, the program starts at `main`
+        let mut result = match body {
+            Some(body) => body,
+            None if self.loaded[&canonical].contains_key("main") => Expr::Call("main".to_string(), vec![]),
+            None => return Err(Error::NoEntryPoint),
+        };
+        let mut definitions = self.sort()?;
+        while let Some(item) = definitions.pop() {
+            result = item.define(result);
+        }
+        Ok(result)
+    }
+
+    /// Order the definitions so that everything is defined before
+    /// it's used, so they can be written in any order
+    fn sort(&mut self) -> Result<Vec<Item>, Error> {
+        let definitions = core::mem::take(&mut self.definitions);
+        let index = definitions.iter().enumerate()
+            .filter_map(|(i, item)| item.name().map(|name| (name.clone(), i)))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut order = vec![];
+        let mut visited = BTreeSet::new();
+        for i in 0..definitions.len() {
+            visit(i, &definitions, &index, &mut visited, &mut vec![], &mut order)?;
+        }
+
+        let mut definitions = definitions.into_iter().map(Some).collect::<Vec<_>>();
+        Ok(order.into_iter().filter_map(|i| definitions[i].take()).collect())
+    }
+
+    /// Load an imported file, if it hasn't been loaded already
+    fn import(&mut self, from: &Path, path: &str) -> Result<Exports, Error> {
+        let dir = from.parent().unwrap_or_else(|| Path::new("."));
+        // Files in the standard library have relative paths, and can import each other
+        let canonical = if Path::new(path).starts_with("std") || is_std(from) {
+            let name = Path::new(path).file_name().unwrap_or_default();
+            Path::new("std").join(name)
+        } else {
+            canonicalize(&dir.join(path))?
+        };
+        if let Some(exports) = self.loaded.get(&canonical) {
+            return Ok(exports.clone());
+        }
+        if let Some(i) = self.loading.iter().position(|p| p == &canonical) {
+            let mut cycle = self.loading[i..].iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>();
+            cycle.push(canonical.display().to_string());
+            return Err(Error::ImportCycle(cycle));
+        }
+
+        let code = if is_std(&canonical) {
+            STD.iter()
+                .find(|(name, _)| canonical.file_name() == Some(name.as_ref()))
+                .map(|(_, code)| code.to_string())
+        } else {
+            fs::read_to_string(&canonical).ok()
+        }.ok_or_else(|| Error::ImportNotFound(path.to_string()))?;
+        let module = parse(&canonical, code)?;
+        let namespace = self.namespace(&canonical);
+
+        self.loading.push(canonical.clone());
+        // Only the declarations of an imported file are used
+        self.define(&canonical, module, Some(namespace))?;
+        self.loading.pop();
+
+        Ok(self.loaded[&canonical].clone())
+    }
+
+    /// Add the functions in a file to the program, and return its body.
+    /// The functions are renamed into the namespace, if there is one, so
+    /// that they don't collide with functions of the same name in other files.
+    fn define(&mut self, path: &Path, module: hir::Module, namespace: Option<String>) -> Result<Option<Expr>, Error> {
+        let mut names = BTreeMap::new();
+        let mut exports = Exports::new();
+        let mut definitions = vec![];
+
+        for item in module.items {
+            match item {
+                Item::Import(import_path, import) => {
+                    let imported = self.import(path, &import_path)?;
+                    match import {
+                        Import::All => names.extend(imported),
+                        Import::Qualified(module) => {
+                            for (name, defined) in imported {
+                                names.insert(format!("{}::{}", module, name), defined);
+                            }
+                        }
+                        Import::Only(only) => {
+                            // Built in functions are already in scope everywhere
+                            for name in only.into_iter().filter(|name| !BUILTINS.contains(&name.as_str())) {
+                                let defined = imported.get(&name)
+                                    .ok_or_else(|| Error::NotExported(name.clone(), import_path.clone()))?;
+                                names.insert(name, defined.clone());
+                            }
+                        }
+                    }
+                }
+                item => {
+                    let name = item.name().cloned().unwrap_or_default();
+                    let defined = match &namespace {
+                        Some(namespace) => format!("{}::{}", namespace, name),
+                        None => name.clone(),
+                    };
+                    names.insert(name.clone(), defined.clone());
+                    exports.insert(name, defined.clone());
+                    definitions.push(match item {
+                        Item::Function(_, params, args, ret, body) => Item::Function(defined, params, args, ret, body),
+                        Item::Const(_, t, val) => Item::Const(defined, t, val),
+                        Item::Global(_, t, val) => Item::Global(defined, t, val),
+                        Item::Import(_, _) => unreachable!(),
+                    });
+                }
+            }
+        }
+
+        for item in definitions {
+            self.definitions.push(item.rename(&names));
+        }
+        self.loaded.insert(path.to_path_buf(), exports);
+        Ok(module.body.map(|body| body.rename(&names)))
+    }
+
+    /// Pick a namespace for a file that isn't used by any other file
+    fn namespace(&mut self, path: &Path) -> String {
+        let stem = path.file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| "module".to_string());
+        let mut namespace = stem.clone();
+        let mut n = 1;
+        while self.namespaces.contains(&namespace) {
+            n += 1;
+            namespace = format!("{}{}", stem, n);
+        }
+        self.namespaces.insert(namespace.clone());
+        namespace
+    }
+}
+
+/// Add a definition to the order after the definitions it uses.
+/// `path` is the definitions being visited, to report recursive definitions.
+fn visit(i: usize, definitions: &[Item], index: &BTreeMap<String, usize>, visited: &mut BTreeSet<usize>, path: &mut Vec<usize>, order: &mut Vec<usize>) -> Result<(), Error> {
+    if let Some(start) = path.iter().position(|j| *j == i) {
+        let mut cycle = path[start..].iter()
+            .filter_map(|j| definitions[*j].name().cloned())
+            .collect::<Vec<_>>();
+        cycle.extend(definitions[i].name().cloned());
+        return Err(Error::RecursiveDefinition(cycle));
+    }
+    if !visited.insert(i) {
+        return Ok(());
+    }
+
+    path.push(i);
+    let mut uses = definitions[i].uses().iter()
+        .filter_map(|name| index.get(name).copied())
+        .collect::<Vec<_>>();
+    uses.sort();
+    for j in uses {
+        visit(j, definitions, index, visited, path, order)?;
+    }
+    path.pop();
+    order.push(i);
+    Ok(())
+}
+
+/// Is this the path of a file in the standard library?
+fn is_std(path: &Path) -> bool {
+    path.is_relative() && path.starts_with("std")
+}
+
+fn canonicalize(path: &Path) -> Result<PathBuf, Error> {
+    fs::canonicalize(path).map_err(|_| Error::ImportNotFound(path.display().to_string()))
+}
+
+fn parse(path: &Path, code: String) -> Result<hir::Module, Error> {
+    hir::parse_module(code).map_err(|e| match e {
+        Error::ParseError(e) => Error::ParseError(format!("in `{}`:\n{}", path.display(), e)),
+        e => e,
+    })
+}
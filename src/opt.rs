@@ -0,0 +1,603 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use super::mir::*;
+
+/// An optimization over MIR code. The pass manager runs each pass
+/// on every sequence of operations in a program: the body of each
+/// `Do`, `Let`, `Frame`, `While`, and `If`.
+pub trait Pass {
+    /// The name of the pass, for debugging
+    fn name(&self) -> &'static str;
+
+    /// Optimize a sequence of operations
+    fn optimize(&self, code: Vec<Op>) -> Vec<Op>;
+}
+
+/// Runs a list of passes over a program until none of them change it
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl PassManager {
+    /// A pass manager without any passes
+    pub fn new() -> Self {
+        Self { passes: vec![] }
+    }
+
+    /// Add a pass to run after the other passes
+    pub fn with(mut self, pass: impl Pass + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// The names of the passes, in the order they run
+    pub fn passes(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+
+    pub fn run(&self, code: Op) -> Op {
+        let mut code = vec![code];
+        loop {
+            let optimized = self.run_sequence(code.clone());
+            if optimized == code {
+                return Op::Do(code);
+            }
+            code = optimized;
+        }
+    }
+
+    fn run_sequence(&self, code: Vec<Op>) -> Vec<Op> {
+        let mut code = code.into_iter().map(|op| self.run_nested(op)).collect();
+        for pass in &self.passes {
+            code = pass.optimize(code);
+        }
+        code
+    }
+
+    fn run_nested(&self, op: Op) -> Op {
+        match op {
+            Op::Do(code) => Op::Do(self.run_sequence(code)),
+            Op::Let(name, val, body) => Op::Let(name, self.run_sequence(val), self.run_sequence(body)),
+            Op::Static(label, data, body) => Op::Static(label, data, self.run_sequence(body)),
+            Op::Frame(args_size, ret_size, code) => Op::Frame(args_size, ret_size, self.run_sequence(code)),
+            Op::While(cond, body) => Op::While(self.run_sequence(cond), self.run_sequence(body)),
+            Op::If(cond, body) => Op::If(self.run_sequence(cond), self.run_sequence(body)),
+            op => op,
+        }
+    }
+}
+
+impl Default for PassManager {
+    /// Every optimization pass
+    fn default() -> Self {
+        Self::new()
+            .with(Peephole)
+            .with(DeadPush)
+            .with(SpillElision)
+    }
+}
+
+/// Removes operations that cancel each other out, and flattens nested `Do`s
+pub struct Peephole;
+
+impl Pass for Peephole {
+    fn name(&self) -> &'static str {
+        "peephole"
+    }
+
+    fn optimize(&self, code: Vec<Op>) -> Vec<Op> {
+        let mut result: Vec<Op> = vec![];
+        for op in code {
+            // Sliding nothing down the stack just frees the cells
+            let op = match op {
+                Op::Slide(0, n) => Op::Stfree(n),
+                op => op,
+            };
+            match op {
+                Op::Do(code) => result.extend(self.optimize(code)),
+                Op::Stalloc(0) | Op::Stfree(0) | Op::Slide(_, 0) => {}
+                op => match (result.pop(), op) {
+                    (Some(Op::Stalloc(a)), Op::Stalloc(b)) => result.push(Op::Stalloc(a + b)),
+                    (Some(Op::Stfree(a)), Op::Stfree(b)) => result.push(Op::Stfree(a + b)),
+                    (Some(Op::Stalloc(a)), Op::Stfree(b)) if a >= b => result.push(Op::Stalloc(a - b)),
+                    (Some(Op::Stalloc(a)), Op::Stfree(b)) => result.push(Op::Stfree(b - a)),
+                    (Some(Op::Push(a)), Op::Pop(b)) if a == b => {}
+                    (last, op) => {
+                        result.extend(last);
+                        result.push(op);
+                    }
+                }
+            }
+        }
+        // Merging can leave behind a `Stalloc(0)` or `Stfree(0)`
+        result.retain(|op| !matches!(op, Op::Stalloc(0) | Op::Stfree(0)));
+        result
+    }
+}
+
+/// Removes values that are pushed onto the stack, only to be freed
+/// without being used.
+pub struct DeadPush;
+
+impl DeadPush {
+    /// The number of cells an operation pops and pushes, if it
+    /// doesn't do anything other than change the top of the stack
+    fn stack_effect(op: &Op) -> Option<(u32, u32)> {
+        Some(match op {
+            Op::PushLiteral(_) | Op::PushAddress(_) | Op::Push(_) => (0, 1),
+            Op::LoadFrom(_, n) | Op::Stalloc(n) => (0, *n),
+            Op::Load(n) => (1, *n),
+            Op::Duplicate => (1, 2),
+            Op::Slide(keep, drop) => (keep + drop, *keep),
+            Op::Not => (1, 1),
+            Op::Increment(loc, _) | Op::Decrement(loc, _) if *loc == SP.deref() => (1, 1),
+            // Division is left alone, because dividing by zero never finishes
+            Op::Add | Op::Sub | Op::Mul | Op::Eq | Op::Neq | Op::And | Op::Or => (2, 1),
+            Op::EqN(n) => (2 * n, 1),
+            _ => return None,
+        })
+    }
+}
+
+impl Pass for DeadPush {
+    fn name(&self) -> &'static str {
+        "dead-push"
+    }
+
+    fn optimize(&self, code: Vec<Op>) -> Vec<Op> {
+        let mut result: Vec<Op> = vec![];
+        for op in code {
+            if let Op::Stfree(mut n) = op {
+                // Remove the operations that pushed the freed cells
+                while let Some((pops, pushes)) = result.last().and_then(Self::stack_effect) {
+                    if pushes > n {
+                        break;
+                    }
+                    result.pop();
+                    n = n - pushes + pops;
+                }
+                if n > 0 {
+                    result.push(Op::Stfree(n));
+                }
+            } else {
+                result.push(op);
+            }
+        }
+        result
+    }
+}
+
+/// Keeps values on the stack instead of moving them through the heap.
+///
+/// A value can be moved down the stack by storing it on the heap, freeing
+/// the cells beneath it, and loading it back, or a member of a tuple can be
+/// taken by storing the tuple on the heap and loading the member. These
+/// are replaced with a `Slide`, which moves the cells without the allocator.
+pub struct SpillElision;
+
+impl SpillElision {
+    /// The stack-only replacement for a spill at the start of `code`,
+    /// and the number of operations it replaces
+    fn elide(code: &[Op]) -> Option<(Vec<Op>, usize)> {
+        use Op::*;
+        match code {
+            // The result of a `Let` body, moved past its variable
+            [PushLiteral(Literal(size)), Alloc, Duplicate, StoreAt(a, 1), Store(s1),
+             Stfree(by), LoadFrom(b, 1), Load(s2), LoadFrom(c, 1), Free, ..]
+                if a == b && b == c && *size == *s1 as u64 && s1 == s2 => {
+                Some((vec![Slide(*s1, *by)], 10))
+            }
+            // A member of a tuple, moved to the start of the tuple
+            [PushLiteral(Literal(size)), Alloc, Duplicate, StoreAt(a, 1), Store(s1),
+             LoadFrom(b, 1), PushLiteral(Literal(before)), Add, Load(member), LoadFrom(c, 1), Free, ..]
+                if a == b && b == c && *size == *s1 as u64 && *before + *member as u64 <= *size => {
+                Some((vec![Stfree(s1 - *before as u32 - member), Slide(*member, *before as u32)], 11))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Pass for SpillElision {
+    fn name(&self) -> &'static str {
+        "spill-elision"
+    }
+
+    fn optimize(&self, code: Vec<Op>) -> Vec<Op> {
+        let mut result = vec![];
+        let mut i = 0;
+        while i < code.len() {
+            if let Some((replacement, replaced)) = Self::elide(&code[i..]) {
+                result.extend(replacement);
+                i += replaced;
+            } else {
+                result.push(code[i].clone());
+                i += 1;
+            }
+        }
+        result
+    }
+}
+
+/// The registers local variables can be stored in. `R5` is left
+/// out, because `CallIndirect` uses it.
+const ALLOCATABLE: [Location; 5] = [R0, R1, R2, R3, R4];
+
+/// The registers that expanding each macro in scope can change
+type Clobbers = BTreeMap<String, BTreeSet<Location>>;
+
+/// Stores scalar local variables in the general purpose registers.
+///
+/// A variable lives in a register instead of the stack frame if it is
+/// one cell wide and its address is never taken. Reading, writing, and
+/// incrementing the variable then use the register directly, instead of
+/// going through the frame pointer.
+///
+/// Registers are allocated by liveness: a variable only holds its register
+/// from where it's stored to its last use, so variables that are never
+/// needed at the same time, like the variables of two loops one after the
+/// other, share a register. A variable can stay in a register across a call
+/// if the function, and everything it calls, leaves that register alone.
+/// The definitions of macros are allocated before the code they're expanded
+/// in, so the registers a function uses are known wherever it's called.
+/// Calling a macro that isn't defined in the program could change any register.
+pub struct RegisterAllocator;
+
+impl RegisterAllocator {
+    pub fn run(&self, code: Op) -> Op {
+        self.allocate_frame(vec![code], &BTreeMap::new()).remove(0)
+    }
+
+    /// Allocate registers in code that doesn't share variables with the code
+    /// around it: the whole program, a frame, or the definition of a macro.
+    fn allocate_frame(&self, code: Vec<Op>, env: &Clobbers) -> Vec<Op> {
+        let code = self.define(code, env);
+        self.allocate_sequence(code, env, &Live::default())
+    }
+
+    /// Allocate registers in the macro definitions and frames in some code
+    fn define(&self, code: Vec<Op>, env: &Clobbers) -> Vec<Op> {
+        code.into_iter().map(|op| match op {
+            Op::Let(name, val, body) => {
+                let val = self.allocate_frame(val, env);
+                let mut env = env.clone();
+                env.insert(name.clone(), clobbers(&val, &env));
+                Op::Let(name, val, self.define(body, &env))
+            }
+            Op::Static(label, data, body) => {
+                let mut env = env.clone();
+                env.insert(label.clone(), BTreeSet::new());
+                Op::Static(label, data, self.define(body, &env))
+            }
+            Op::Do(code) => Op::Do(self.define(code, env)),
+            Op::Frame(args_size, ret_size, code) => Op::Frame(args_size, ret_size, self.allocate_frame(code, env)),
+            Op::While(cond, body) => Op::While(self.define(cond, env), self.define(body, env)),
+            Op::If(cond, body) => Op::If(self.define(cond, env), self.define(body, env)),
+            op => op,
+        }).collect()
+    }
+
+    /// Allocate registers to the variables declared in some code, given
+    /// the registers that are live after it
+    fn allocate_sequence(&self, code: Vec<Op>, env: &Clobbers, after: &Live) -> Vec<Op> {
+        let mut liveness = Liveness::default();
+        let mut afters = vec![after.clone(); code.len()];
+        let mut live = after.clone();
+        for i in (0..code.len()).rev() {
+            afters[i] = live.clone();
+            live = liveness.op(&code, i, None, env, live);
+        }
+        code.into_iter().zip(afters).map(|(op, after)| self.allocate(op, env, &after)).collect()
+    }
+
+    fn allocate(&self, op: Op, env: &Clobbers, after: &Live) -> Op {
+        match op {
+            Op::Let(name, val, body) => {
+                let mut env = env.clone();
+                env.insert(name.clone(), clobbers(&val, &env));
+                let (val, body) = match Self::choose_register(&name, &val, &body, &env, after) {
+                    Some(Location::Address(register)) => {
+                        let body = replace_uses(body, &name, &Location::Address(register));
+                        (vec![Op::PushAddress(register)], body)
+                    }
+                    Some(_) => unreachable!("registers have fixed addresses"),
+                    None => (val, body),
+                };
+                Op::Let(name, val, self.allocate_sequence(body, &env, after))
+            }
+            Op::Static(label, data, body) => {
+                let mut env = env.clone();
+                env.insert(label.clone(), BTreeSet::new());
+                Op::Static(label, data, self.allocate_sequence(body, &env, after))
+            }
+            Op::Do(code) => Op::Do(self.allocate_sequence(code, env, after)),
+            Op::While(cond, body) => {
+                let mut liveness = Liveness::default();
+                let entry = liveness.loop_entry(&cond, &body, None, env, after);
+                let after_cond = after.union(&liveness.sequence(&body, None, env, entry.clone()));
+                Op::While(self.allocate_sequence(cond, env, &after_cond), self.allocate_sequence(body, env, &entry))
+            }
+            Op::If(cond, body) => {
+                let after_cond = after.union(&Liveness::default().sequence(&body, None, env, after.clone()));
+                Op::If(self.allocate_sequence(cond, env, &after_cond), self.allocate_sequence(body, env, after))
+            }
+            // Frames and macro definitions were allocated on their own
+            op => op,
+        }
+    }
+
+    /// A register for a variable, if it can be stored in one. The register
+    /// can't be live, or changed, anywhere the variable is live.
+    fn choose_register(name: &str, val: &[Op], body: &[Op], env: &Clobbers, after: &Live) -> Option<Location> {
+        // Only variables in the stack frame are moved into registers
+        if !matches!(val, [Op::LoadFrom(fp, 1), Op::PushLiteral(_), Op::Add] if *fp == FP) {
+            return None;
+        }
+        if !only_direct_uses(body, name) {
+            return None;
+        }
+        let mut liveness = Liveness::default();
+        liveness.sequence(body, Some(name), env, Live { variable: false, registers: after.registers.clone() });
+        ALLOCATABLE.into_iter().find(|register| !liveness.conflicts.contains(register))
+    }
+}
+
+/// What's live at a point in the code: whether the variable being
+/// allocated is, and which registers are
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Live {
+    variable: bool,
+    registers: BTreeSet<Location>,
+}
+
+impl Live {
+    fn union(&self, other: &Self) -> Self {
+        Self {
+            variable: self.variable || other.variable,
+            registers: self.registers.union(&other.registers).cloned().collect(),
+        }
+    }
+}
+
+/// Works backwards through code to find what's live before each operation.
+/// Along the way, it records the registers that conflict with a variable:
+/// the ones that are live, or changed, while the variable is live.
+#[derive(Default)]
+struct Liveness {
+    conflicts: BTreeSet<Location>,
+}
+
+impl Liveness {
+    /// What's live before some code, given what's live after it
+    fn sequence(&mut self, code: &[Op], name: Option<&str>, env: &Clobbers, after: Live) -> Live {
+        let mut live = after;
+        for i in (0..code.len()).rev() {
+            live = self.op(code, i, name, env, live);
+        }
+        live
+    }
+
+    /// What's live at the start of each iteration of a loop
+    fn loop_entry(&mut self, cond: &[Op], body: &[Op], name: Option<&str>, env: &Clobbers, after: &Live) -> Live {
+        let mut entry = Live::default();
+        loop {
+            let before_body = self.sequence(body, name, env, entry.clone());
+            let before_cond = self.sequence(cond, name, env, after.union(&before_body));
+            if before_cond == entry {
+                return entry;
+            }
+            entry = before_cond;
+        }
+    }
+
+    /// What's live before the operation at `i` in some code, given what's live after it
+    fn op(&mut self, code: &[Op], i: usize, name: Option<&str>, env: &Clobbers, after: Live) -> Live {
+        let mut before = after.clone();
+        let mut touches_variable = false;
+        let changed = match &code[i] {
+            Op::Macro(m) if Some(m.as_str()) == name => {
+                // Storing to the variable gives it a new value, and any other use reads it
+                touches_variable = true;
+                before.variable = !matches!(code.get(i + 1), Some(Op::Store(_)));
+                BTreeSet::new()
+            }
+            Op::Macro(m) => env.get(m).cloned().unwrap_or_else(|| ALLOCATABLE.into()),
+            Op::Expand(m, args) => {
+                let mut changed = env.get(m).cloned().unwrap_or_else(|| ALLOCATABLE.into());
+                for arg in args {
+                    changed.extend(clobbers(arg, env));
+                }
+                // The arguments could be expanded anywhere in the macro
+                before.registers.extend(changed.iter().cloned());
+                changed
+            }
+            Op::CallIndirect(signature) => indirect_clobbers(signature, env),
+            Op::Frame(_, _, code) => clobbers(code, env),
+            Op::Pop(register) | Op::StoreAt(register, 1) if ALLOCATABLE.contains(register) => {
+                before.registers.remove(register);
+                BTreeSet::from([register.clone()])
+            }
+            Op::Let(m, val, body) => {
+                let mut env = env.clone();
+                env.insert(m.clone(), clobbers(val, &env));
+                // The variable is shadowed in the body of a `Let` with the same name
+                let inner = if Some(m.as_str()) == name { None } else { name };
+                return self.sequence(body, inner, &env, after);
+            }
+            Op::Static(label, _, body) => {
+                let mut env = env.clone();
+                env.insert(label.clone(), BTreeSet::new());
+                let inner = if Some(label.as_str()) == name { None } else { name };
+                return self.sequence(body, inner, &env, after);
+            }
+            Op::Do(code) => return self.sequence(code, name, env, after),
+            Op::While(cond, body) => return self.loop_entry(cond, body, name, env, &after),
+            Op::If(cond, body) => {
+                let before_body = self.sequence(body, name, env, after.clone());
+                return self.sequence(cond, name, env, after.union(&before_body));
+            }
+            Op::Template(_, _) => BTreeSet::new(),
+            op => {
+                let used = registers(op);
+                before.registers.extend(used.iter().cloned());
+                used
+            }
+        };
+        if touches_variable || after.variable || before.variable {
+            self.conflicts.extend(after.registers);
+            self.conflicts.extend(before.registers.iter().cloned());
+            self.conflicts.extend(changed);
+        }
+        before
+    }
+}
+
+/// The registers that running some code can change
+fn clobbers(code: &[Op], env: &Clobbers) -> BTreeSet<Location> {
+    let mut result = BTreeSet::new();
+    for op in code {
+        match op {
+            Op::Macro(m) => result.extend(env.get(m).cloned().unwrap_or_else(|| ALLOCATABLE.into())),
+            Op::Expand(m, args) => {
+                result.extend(env.get(m).cloned().unwrap_or_else(|| ALLOCATABLE.into()));
+                for arg in args {
+                    result.extend(clobbers(arg, env));
+                }
+            }
+            Op::CallIndirect(signature) => result.extend(indirect_clobbers(signature, env)),
+            Op::Let(name, val, body) => {
+                let mut env = env.clone();
+                env.insert(name.clone(), clobbers(val, &env));
+                result.extend(clobbers(body, &env));
+            }
+            Op::Static(label, _, body) => {
+                let mut env = env.clone();
+                env.insert(label.clone(), BTreeSet::new());
+                result.extend(clobbers(body, &env));
+            }
+            Op::Template(params, code) => {
+                // The arguments' registers are counted where the macro is expanded
+                let mut env = env.clone();
+                for param in params {
+                    env.insert(param.clone(), BTreeSet::new());
+                }
+                result.extend(clobbers(code, &env));
+            }
+            Op::Do(code) | Op::Frame(_, _, code) => result.extend(clobbers(code, env)),
+            Op::While(cond, body) | Op::If(cond, body) => {
+                result.extend(clobbers(cond, env));
+                result.extend(clobbers(body, env));
+            }
+            op => result.extend(registers(op)),
+        }
+    }
+    result
+}
+
+/// The registers an indirect call can change: the ones that any function
+/// with its signature changes
+fn indirect_clobbers(signature: &str, env: &Clobbers) -> BTreeSet<Location> {
+    let prefix = format!("{}#", signature);
+    env.iter()
+        .filter(|(name, _)| name.strip_prefix(&prefix).is_some_and(|index| index.parse::<u32>().is_ok()))
+        .flat_map(|(_, registers)| registers.iter().cloned())
+        .collect()
+}
+
+/// Whether every use of a variable in some code reads, writes, increments,
+/// or decrements it, so that its uses can be replaced with a register.
+/// Macro definitions, frames, and macro arguments can't use it at all.
+fn only_direct_uses(code: &[Op], name: &str) -> bool {
+    code.iter().enumerate().all(|(i, op)| match op {
+        Op::Macro(m) if m == name => is_direct_use(&code[i + 1..]),
+        Op::Let(m, val, body) => !mentions(val, name) && (m == name || only_direct_uses(body, name)),
+        Op::Static(label, _, body) => label == name || only_direct_uses(body, name),
+        Op::Do(code) => only_direct_uses(code, name),
+        Op::While(cond, body) | Op::If(cond, body) => only_direct_uses(cond, name) && only_direct_uses(body, name),
+        Op::Frame(_, _, code) | Op::Template(_, code) => !mentions(code, name),
+        Op::Expand(m, args) => m != name && args.iter().all(|arg| !mentions(arg, name)),
+        _ => true,
+    })
+}
+
+/// Whether some code refers to a macro by name anywhere
+fn mentions(code: &[Op], name: &str) -> bool {
+    code.iter().any(|op| match op {
+        Op::Macro(m) | Op::Sized(m, _) => m == name,
+        Op::Expand(m, args) => m == name || args.iter().any(|arg| mentions(arg, name)),
+        Op::Let(_, val, body) | Op::While(val, body) | Op::If(val, body) => mentions(val, name) || mentions(body, name),
+        Op::Do(code) | Op::Static(_, _, code) | Op::Frame(_, _, code) | Op::Template(_, code) => mentions(code, name),
+        _ => false,
+    })
+}
+
+/// Whether the operations after a variable's address read, write,
+/// increment, or decrement it
+fn is_direct_use(rest: &[Op]) -> bool {
+    match rest {
+        [Op::Load(1) | Op::Store(1), ..] => true,
+        [Op::Increment(loc, _) | Op::Decrement(loc, _), Op::Stfree(1), ..] => *loc == SP.deref().deref(),
+        _ => false,
+    }
+}
+
+/// The allocatable registers an operation uses, to read or write them
+/// or to find the locations it reads and writes
+fn registers(op: &Op) -> BTreeSet<Location> {
+    fn base(loc: &Location) -> Location {
+        match loc {
+            Location::Deref(inner) | Location::Offset(inner, _) => base(inner),
+            loc => loc.clone(),
+        }
+    }
+    let cells = match op {
+        // Moving several cells at a register's address uses the registers after it too
+        Op::StoreAt(Location::Address(Address(address)), size) | Op::LoadFrom(Location::Address(Address(address)), size) => {
+            (*address..*address + size).map(|address| Location::Address(Address(address))).collect()
+        }
+        Op::Push(loc) | Op::Pop(loc) | Op::StoreAt(loc, _) | Op::LoadFrom(loc, _)
+        | Op::Increment(loc, _) | Op::Decrement(loc, _) => vec![base(loc)],
+        Op::PushAddress(address) => vec![Location::Address(*address)],
+        _ => vec![],
+    };
+    cells.into_iter().filter(|cell| ALLOCATABLE.contains(cell)).collect()
+}
+
+/// Replace the reads and writes of a variable with uses of its register
+fn replace_uses(code: Vec<Op>, name: &str, register: &Location) -> Vec<Op> {
+    let mut result = vec![];
+    let mut code = code.into_iter();
+    while let Some(op) = code.next() {
+        match op {
+            Op::Macro(m) if m == name => match code.next() {
+                Some(Op::Load(_)) => result.push(Op::Push(register.clone())),
+                Some(Op::Store(_)) => result.push(Op::Pop(register.clone())),
+                Some(Op::Increment(_, n)) => {
+                    code.next();
+                    result.push(Op::Increment(register.clone(), n));
+                }
+                Some(Op::Decrement(_, n)) => {
+                    code.next();
+                    result.push(Op::Decrement(register.clone(), n));
+                }
+                next => {
+                    result.push(Op::Macro(m));
+                    result.extend(next);
+                }
+            },
+            Op::Let(m, val, body) => {
+                let val = replace_uses(val, name, register);
+                let body = if m == name { body } else { replace_uses(body, name, register) };
+                result.push(Op::Let(m, val, body));
+            }
+            Op::Static(label, data, body) => {
+                let body = if label == name { body } else { replace_uses(body, name, register) };
+                result.push(Op::Static(label, data, body));
+            }
+            Op::Do(code) => result.push(Op::Do(replace_uses(code, name, register))),
+            Op::While(cond, body) => result.push(Op::While(replace_uses(cond, name, register), replace_uses(body, name, register))),
+            Op::If(cond, body) => result.push(Op::If(replace_uses(cond, name, register), replace_uses(body, name, register))),
+            op => result.push(op),
+        }
+    }
+    result
+}
@@ -0,0 +1,118 @@
+use core::{fmt, str::FromStr};
+
+/// The number of bits in each cell on the tape
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CellWidth {
+    Bits8,
+    Bits16,
+    Bits32,
+    Bits64,
+}
+
+impl CellWidth {
+    pub fn bits(&self) -> u32 {
+        match self {
+            Self::Bits8 => 8,
+            Self::Bits16 => 16,
+            Self::Bits32 => 32,
+            Self::Bits64 => 64,
+        }
+    }
+
+    /// The largest value a cell can hold
+    pub fn max_value(&self) -> u64 {
+        match self {
+            Self::Bits64 => u64::MAX,
+            _ => (1 << self.bits()) - 1,
+        }
+    }
+}
+
+impl fmt::Display for CellWidth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.bits())
+    }
+}
+
+impl FromStr for CellWidth {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(Self::Bits8),
+            "16" => Ok(Self::Bits16),
+            "32" => Ok(Self::Bits32),
+            "64" => Ok(Self::Bits64),
+            _ => Err(format!("invalid cell width `{}`: expected 8, 16, 32, or 64", s)),
+        }
+    }
+}
+
+/// What happens when a cell is incremented past its maximum value,
+/// or decremented below zero
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Overflow {
+    /// Wrap around to the other end of the range
+    Wrap,
+    /// Stay at the end of the range
+    Saturate,
+    /// Stop the program with an error
+    Trap,
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Wrap => write!(f, "wrap"),
+            Self::Saturate => write!(f, "saturate"),
+            Self::Trap => write!(f, "trap"),
+        }
+    }
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(Self::Wrap),
+            "saturate" => Ok(Self::Saturate),
+            "trap" => Ok(Self::Trap),
+            _ => Err(format!("invalid overflow behavior `{}`: expected wrap, saturate, or trap", s)),
+        }
+    }
+}
+
+/// The machine that a program is compiled for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Target {
+    pub cell_width: CellWidth,
+    pub overflow: Overflow,
+}
+
+impl Target {
+    pub fn new(cell_width: CellWidth, overflow: Overflow) -> Self {
+        Self { cell_width, overflow }
+    }
+
+    /// Can a cell on this target hold the value `n`?
+    pub fn fits(&self, n: u64) -> bool {
+        n <= self.cell_width.max_value()
+    }
+
+    /// The number of cells on the tape. Every address
+    /// on the tape must fit in a single cell.
+    pub fn tape_size(&self) -> u64 {
+        core::cmp::min(30000, self.cell_width.max_value())
+    }
+}
+
+impl Default for Target {
+    fn default() -> Self {
+        Self::new(CellWidth::Bits32, Overflow::Wrap)
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-bit {}", self.cell_width, self.overflow)
+    }
+}
@@ -0,0 +1,120 @@
+use harborc::hir::{parse, Error, Expr, Node, Type, Typed};
+use std::collections::BTreeMap;
+
+/// Parse and type check an expression
+fn check(code: String) -> Typed {
+    // Parsing takes more stack than test threads have by default
+    std::thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(move || parse(code).unwrap().check(&BTreeMap::new()).unwrap())
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
+/// Parse an expression, and return the error from type checking it
+fn check_error(code: String) -> Error {
+    std::thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(move || parse(code).unwrap().check(&BTreeMap::new()).unwrap_err())
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
+#[test]
+fn annotations() {
+    let typed = check("let x = (1, 'a') in x.1".to_string());
+    assert_eq!(typed.t, Type::Character);
+    assert_eq!(typed.size, 1);
+
+    // The inferred `let` is checked once, and keeps the type of its value
+    if let Node::Let(name, val, body) = typed.node {
+        assert_eq!(name, "x");
+        assert_eq!(val.t, Type::Tuple(vec![Type::Integer, Type::Character]));
+        assert_eq!(val.size, 2);
+        if let Node::Nth(tup, size_before) = body.node {
+            assert_eq!(tup.node, Node::Variable("x".to_string()));
+            assert_eq!(tup.size, 2);
+            assert_eq!(size_before, 1);
+        } else {
+            panic!("expected a tuple member");
+        }
+    } else {
+        panic!("expected a let");
+    }
+}
+
+#[test]
+fn nested_values() {
+    // Each value is only checked once, so this doesn't take exponential time
+    let mut code = "1".to_string();
+    for i in 0..24 {
+        code = format!("let x{} = ({}) in x{}", i, code, i);
+    }
+    let typed = check(code);
+    assert_eq!(typed.t, Type::Integer);
+
+    // Values that refer to the variable they shadow are only checked once too
+    let mut code = "x".to_string();
+    for _ in 0..24 {
+        code = format!("let x = x + ({}) in x", code);
+    }
+    let typed = check(format!("let x = 1 in {}", code));
+    assert_eq!(typed.t, Type::Integer);
+
+    // A local function can call itself with the type it declares
+    let typed = check("fn f(n: int) -> int = f(n - 1) in f(5)".to_string());
+    assert_eq!(typed.t, Type::Integer);
+}
+
+#[test]
+fn patterns() {
+    let typed = check("let ((x, y), c) = ((1, 2), 'a') in c".to_string());
+    assert_eq!(typed.t, Type::Character);
+
+    // Each name in the pattern is stored as its own variable
+    if let Node::Destructure(names, val, _) = typed.node {
+        assert_eq!(names, vec![("x".to_string(), 1), ("y".to_string(), 1), ("c".to_string(), 1)]);
+        assert_eq!(val.size, 3);
+    } else {
+        panic!("expected a destructuring let");
+    }
+    // A name can only be bound once
+    let e = check_error("let ((x, y), x) = ((1, 2), 3) in x".to_string());
+    assert_eq!(e, Error::DuplicateName("x".to_string()));
+    let e = check_error("fn f((a, b): (int, int), b: int) -> int = a + b in f((1, 2), 3)".to_string());
+    assert_eq!(e, Error::DuplicateName("b".to_string()));
+}
+
+#[test]
+fn arrays() {
+    let typed = check("[(1, 'a'), (2, 'b')]".to_string());
+    assert_eq!(typed.t, Type::Pointer(Box::new(Type::Tuple(vec![Type::Integer, Type::Character]))));
+
+    // Array items are stored in static data, so they have to be constants
+    let e = check_error("let x = 1 in [2, x]".to_string());
+    assert!(matches!(e, Error::NonConstantArray(..)), "{:?}", e);
+    // An empty array has no items to take its type from
+    let e = Expr::Array(vec![]).check(&BTreeMap::new()).unwrap_err();
+    assert_eq!(e, Error::EmptyArray(Expr::Array(vec![])));
+}
+
+#[test]
+fn generics() {
+    // Each instance is checked once, and defined where the generic function is
+    let typed = check("fn id<T>(x: T) -> T = x in (id(1), id('a'), id(2), id(id('b')))".to_string());
+    assert_eq!(typed.t, Type::Tuple(vec![Type::Integer, Type::Character, Type::Integer, Type::Character]));
+    if let Node::LetGeneric(instances, _) = typed.node {
+        let names = instances.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["id<int, >", "id<char, >"]);
+    } else {
+        panic!("expected the instances of a generic function");
+    }
+
+    // Generic functions can't call themselves, with any types
+    let e = check_error("fn f<T>(x: T) -> T = f(x) in f(1)".to_string());
+    assert_eq!(e, Error::RecursiveDefinition(vec!["f".to_string(), "f".to_string()]));
+    let e = check_error("fn f<T>(x: T) -> int = f('a') in f(1)".to_string());
+    assert_eq!(e, Error::RecursiveDefinition(vec!["f".to_string(), "f".to_string()]));
+}
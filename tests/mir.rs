@@ -0,0 +1,206 @@
+use harborc::{lir::Program, mir::*, target::Target};
+
+/// Assemble a MIR program and run it in the interpreter
+fn run(code: Op) -> Result<String, Error> {
+    let mut program = Program::default();
+    code.assemble(&mut program)?;
+
+    let mut output = vec![];
+    program.run(&Target::default(), &b""[..], &mut output).unwrap();
+    Ok(String::from_utf8(output).unwrap())
+}
+
+#[test]
+fn set() {
+    let code = Op::Do(vec![
+        Op::PushLiteral(Literal(1)),
+        Op::Alloc,
+        Op::Duplicate,
+        Op::Set(Literal(7)),
+        Op::Load(1),
+        Op::Putnum,
+    ]);
+    assert_eq!(run(code), Ok("7".to_string()));
+}
+
+#[test]
+fn validation() {
+    // Storing two cells when only the address is on the stack
+    let code = Op::Do(vec![Op::PushLiteral(Literal(1)), Op::Alloc, Op::Store(2)]);
+    assert_eq!(run(code), Err(Error::StackUnderflow(Op::Store(2))));
+
+    // A conditional that only sometimes pushes a value
+    let code = Op::If(vec![Op::Getchar], vec![Op::PushLiteral(Literal(1))]);
+    assert_eq!(code.validate(), Err(Error::UnbalancedBlock("if", 1)));
+
+    // A loop whose condition leaves a value for the body to consume is fine
+    let code = Op::While(vec![Op::Getchar, Op::Duplicate], vec![Op::Putchar]);
+    assert_eq!(code.validate(), Ok(()));
+
+    let code = Op::Frame(0, 1, vec![Op::Getchar, Op::Getchar]);
+    assert_eq!(code.validate(), Err(Error::FrameSize(1, 2)));
+
+    // An indirect call with no functions of its signature to choose from
+    let code = Op::Do(vec![Op::PushLiteral(Literal(1)), Op::CallIndirect("() -> void".to_string())]);
+    assert_eq!(code.validate(), Err(Error::NoCallTarget("() -> void".to_string())));
+}
+
+#[test]
+fn verification_context() {
+    // Errors say which block or macro they happened in, outermost first
+    let code = Op::Let(
+        "f".to_string(),
+        vec![Op::Putnum],
+        vec![Op::While(vec![Op::Getchar], vec![Op::Macro("f".to_string())])],
+    );
+    assert_eq!(
+        code.validate(),
+        Err(Error::Within(
+            "while body".to_string(),
+            Box::new(Error::Within(
+                "macro 'f'".to_string(),
+                Box::new(Error::StackUnderflow(Op::Putnum))
+            ))
+        ))
+    );
+    assert!(code.validate().unwrap_err().to_string().ends_with(
+        "in while body: in macro 'f': Putnum pops more cells than there are on the stack"
+    ));
+}
+
+#[test]
+fn macro_parameters() {
+    let code = parse("
+        let
+            add(a, b) = (a b +),
+            shadow(x) = let y = 1 in x y + end,
+            cells(n) = (get %n dump %n)
+        in
+            add!(%(%int, %int), 3) putnum
+            let y = 100 in shadow!(y) putnum end
+            cells!(2)
+        end
+    ").unwrap();
+    let code = Op::Let("putnum".to_string(), vec![Op::Putnum], vec![code]);
+    // The `y` bound in `shadow` doesn't capture the `y` passed to it
+    assert_eq!(run(code), Ok("5101".to_string()));
+
+    // An argument named like a later parameter isn't substituted again
+    let code = parse("let b = 7 in let f(a, b) = (a b +) in f!(b, 1) putnum end end").unwrap();
+    let code = Op::Let("putnum".to_string(), vec![Op::Putnum], vec![code]);
+    assert_eq!(run(code), Ok("8".to_string()));
+
+    let code = parse("let f(n) = (get %n) in f!(1 2) end").unwrap();
+    assert_eq!(code.validate(), Err(Error::Within(
+        "macro 'f'".to_string(),
+        Box::new(Error::SizeParameter("n".to_string()))
+    )));
+
+    let code = parse("let f(n) = n in f!(1, 2) end").unwrap();
+    assert_eq!(code.validate(), Err(Error::ArgumentCount("f".to_string(), 1, 2)));
+
+    // The names bound in a macro are renamed the same way every time it's assembled
+    let code = parse("let f(n) = let g = (n +) in g end in f!(1) end").unwrap();
+    let error = code.validate();
+    assert!(matches!(error, Err(Error::Within(_, _))), "{:?}", error);
+    assert_eq!(code.validate(), error);
+}
+
+#[test]
+fn round_trip() {
+    // Compiler output can be written as MIR and parsed again
+    std::thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(|| {
+            for path in ["examples/closure.hb", "examples/callback.hb", "examples/generic.hb", "examples/signed.hb", "examples/std.hb"] {
+                let target = Target::default();
+                let code = std::fs::read_to_string(path).unwrap();
+                let expr = harborc::module::load(path, code).unwrap();
+                let op = expr.check(&std::collections::BTreeMap::new()).unwrap().compile(&mut 0, &mut 0, &target).unwrap();
+                let op = harborc::opt::RegisterAllocator.run(harborc::opt::PassManager::default().run(op));
+
+                let parsed = parse(op.to_string()).unwrap();
+                let mut expected = Program::default();
+                let mut found = Program::default();
+                op.assemble(&mut expected).unwrap();
+                parsed.assemble(&mut found).unwrap();
+                assert_eq!(expected.to_string(), found.to_string(), "{}", path);
+            }
+        })
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
+#[test]
+fn locations() {
+    let code = parse("(push [SP@ -1 +] pop [R0] [FP 2 +] += 3 load [R1@] %2 &[R4] slide %1 %(%int, %int))").unwrap();
+    assert_eq!(code, Op::Do(vec![
+        Op::Push(SP.deref().offset(-1)),
+        Op::Pop(R0),
+        Op::Increment(FP.offset(2), 3),
+        Op::LoadFrom(R1.deref(), 2),
+        Op::PushAddress(R4.get_address().unwrap()),
+        Op::Slide(1, 2),
+    ]));
+    assert_eq!(code.to_string(), "(push [SP@ -1 +] pop [R0] [FP 2 +] += 3 load [R1@] %2 &[R4] slide %1 %2)");
+
+    // Only registers and numbered cells have addresses
+    assert!(parse("&[R0@]").is_err());
+}
+
+#[test]
+fn static_data() {
+    let code = parse("data s = \"hi\" 0, n = 7 in n @ putnum s 1 + @ putchar 'x' putchar end").unwrap();
+    let (linked, data) = code.link();
+    assert_eq!(data, vec![Literal('h' as u64), Literal('i' as u64), Literal(0), Literal(7)]);
+    // The labels push the addresses of their data after the registers
+    assert_eq!(linked.to_string(), format!(
+        "let s = {} in let n = {} in n @ %1 putnum s 1 + @ %1 putchar 120 putchar end end",
+        TOTAL_REGISTERS, TOTAL_REGISTERS + 3
+    ));
+    assert_eq!(run(code), Ok("7ix".to_string()));
+
+    // Data can be as wide as a 64-bit cell
+    let (_, data) = parse("data n = 5000000000 in n end").unwrap().link();
+    assert_eq!(data, vec![Literal(5_000_000_000)]);
+}
+
+#[test]
+fn multi_cell_equality() {
+    // The cells beneath the compared values are left alone
+    let code = parse("(9 1 2 3 1 2 3 == %3 putnum 1 2 3 1 5 3 == %3 putnum putnum)").unwrap();
+    assert_eq!(run(code), Ok("109".to_string()));
+
+    let code = parse("let eq(n) = (== %n) in 4 4 eq!(1) eq!(0) + putnum end").unwrap();
+    assert_eq!(run(code), Ok("2".to_string()));
+}
+
+
+#[test]
+fn direct_access() {
+    let assembled = |code: &str| {
+        let mut program = Program::default();
+        parse(code).unwrap().assemble(&mut program).unwrap();
+        program.to_string()
+    };
+    // A macro that pushes a fixed address is used as the address itself
+    assert_eq!(assembled("let g = 20 in g @ %2 end"), assembled("load [20] %2"));
+    assert_eq!(assembled("let g = 20 in 1 2 g = %2 end"), assembled("(1 2 store [20] %2)"));
+    assert_eq!(assembled("let g = 20 in g [SP@@] += 3 dump %1 end"), assembled("[20] += 3"));
+    assert_eq!(assembled("let g = 20 in g [SP@@] -= 3 dump %1 end"), assembled("[20] -= 3"));
+    // Addresses that are computed, or used for anything else, are pushed as usual
+    assert_ne!(assembled("let g = (10 10 +) in g @ %1 end"), assembled("load [20] %1"));
+    assert_eq!(assembled("let g = 20 in g putchar end"), assembled("(20 putchar)"));
+
+    let code = parse("
+        data g = 0 0 in
+            4 5 g = %2
+            g [SP@@] += 3 dump %1
+            g 1 + [SP@@] -= 2 dump %1
+            g @ %2 putnum putnum
+        end
+    ").unwrap();
+    let code = Op::Let("putnum".to_string(), vec![Op::Putnum], vec![code]);
+    assert_eq!(run(code), Ok("37".to_string()));
+}
@@ -0,0 +1,218 @@
+use harborc::{mir::*, opt::*};
+
+#[test]
+fn peephole() {
+    let code = vec![
+        Op::Do(vec![Op::Stalloc(2), Op::Do(vec![])]),
+        Op::Stfree(2),
+        Op::Push(R0),
+        Op::Pop(R0),
+        Op::Stfree(1),
+        Op::Slide(2, 0),
+        Op::Slide(0, 3),
+    ];
+    assert_eq!(Peephole.optimize(code), vec![Op::Stfree(4)]);
+}
+
+#[test]
+fn dead_push() {
+    let code = vec![
+        Op::Putnum,
+        Op::PushLiteral(Literal(1)),
+        Op::LoadFrom(FP, 2),
+        Op::Add,
+        Op::Slide(1, 1),
+        Op::Stfree(2),
+    ];
+    assert_eq!(DeadPush.optimize(code), vec![Op::Putnum, Op::Stfree(1)]);
+
+    // Only values that are freed are removed
+    let code = vec![Op::Getchar, Op::LoadFrom(FP, 3), Op::Stfree(1)];
+    assert_eq!(DeadPush.optimize(code.clone()), code);
+}
+
+#[test]
+fn spill_elision() {
+    let let_result = vec![
+        Op::PushLiteral(Literal(1)),
+        Op::Alloc,
+        Op::Duplicate,
+        Op::StoreAt(R2, 1),
+        Op::Store(1),
+        Op::Stfree(2),
+        Op::LoadFrom(R2, 1),
+        Op::Load(1),
+        Op::LoadFrom(R2, 1),
+        Op::Free,
+    ];
+    assert_eq!(SpillElision.optimize(let_result), vec![Op::Slide(1, 2)]);
+
+    let second_of_three = vec![
+        Op::PushLiteral(Literal(3)),
+        Op::Alloc,
+        Op::Duplicate,
+        Op::StoreAt(R1, 1),
+        Op::Store(3),
+        Op::LoadFrom(R1, 1),
+        Op::PushLiteral(Literal(1)),
+        Op::Add,
+        Op::Load(1),
+        Op::LoadFrom(R1, 1),
+        Op::Free,
+    ];
+    assert_eq!(SpillElision.optimize(second_of_three), vec![
+        Op::Stfree(1),
+        Op::Slide(1, 1),
+    ]);
+}
+
+#[test]
+fn pass_manager() {
+    let manager = PassManager::default();
+    assert_eq!(manager.passes(), vec!["peephole", "dead-push", "spill-elision"]);
+
+    let code = Op::Do(vec![
+        Op::While(vec![Op::Push(R0)], vec![
+            Op::Stalloc(1),
+            Op::Do(vec![Op::Stfree(1)]),
+            Op::Putnum,
+        ]),
+    ]);
+    assert_eq!(manager.run(code), Op::Do(vec![
+        Op::While(vec![Op::Push(R0)], vec![Op::Putnum]),
+    ]));
+}
+
+#[test]
+fn register_allocation() {
+    let local = |name: &str, body: Vec<Op>| {
+        Op::Let(name.to_string(), vec![Op::LoadFrom(FP, 1), Op::PushLiteral(Literal(0)), Op::Add], body)
+    };
+    let read = |name: &str| vec![Op::Macro(name.to_string()), Op::Load(1)];
+    let write = |name: &str| vec![Op::Macro(name.to_string()), Op::Store(1)];
+
+    // A loop counter is moved into a register
+    let code = local("i", [
+        vec![Op::Stalloc(1), Op::PushLiteral(Literal(0))],
+        write("i"),
+        vec![Op::While(read("i"), vec![
+            Op::Macro("i".to_string()),
+            Op::Increment(SP.deref().deref(), 1),
+            Op::Stfree(1),
+        ])],
+    ].concat());
+    assert_eq!(RegisterAllocator.run(code), Op::Let("i".to_string(), vec![Op::PushAddress(Address(8))], vec![
+        Op::Stalloc(1),
+        Op::PushLiteral(Literal(0)),
+        Op::Pop(R0),
+        Op::While(vec![Op::Push(R0)], vec![Op::Increment(R0, 1)]),
+    ]));
+
+    // Variables whose address is taken stay on the stack
+    let code = local("x", [vec![Op::Stalloc(1), Op::Getchar], write("x"), vec![Op::Macro("x".to_string()), Op::Putnum]].concat());
+    assert_eq!(RegisterAllocator.run(code.clone()), code);
+
+    // So do variables live during a call to a macro whose registers aren't known
+    let code = local("x", [vec![Op::Stalloc(1), Op::Getchar], write("x"), vec![Op::Macro("f".to_string())], read("x")].concat());
+    assert_eq!(RegisterAllocator.run(code.clone()), code);
+
+    // A variable stays in a register across a call to a function that doesn't use it
+    let code = Op::Let("f".to_string(), vec![Op::PushLiteral(Literal(1)), Op::Pop(R0)], vec![
+        local("x", [vec![Op::Stalloc(1), Op::Getchar], write("x"), vec![Op::Macro("f".to_string())], read("x")].concat()),
+    ]);
+    assert_eq!(RegisterAllocator.run(code), Op::Let("f".to_string(), vec![Op::PushLiteral(Literal(1)), Op::Pop(R0)], vec![
+        Op::Let("x".to_string(), vec![Op::PushAddress(Address(9))], vec![
+            Op::Stalloc(1),
+            Op::Getchar,
+            Op::Pop(R1),
+            Op::Macro("f".to_string()),
+            Op::Push(R1),
+        ]),
+    ]));
+
+    // A variable keeps its register through a nested scope, which gets the next register
+    let code = local("i", [
+        vec![Op::Stalloc(1), Op::Getchar],
+        write("i"),
+        vec![Op::While(read("i"), vec![
+            local("j", [vec![Op::Stalloc(1)], read("i"), write("j"), read("j"), vec![Op::Putnum, Op::Stfree(1)]].concat()),
+            Op::Macro("i".to_string()),
+            Op::Decrement(SP.deref().deref(), 1),
+            Op::Stfree(1),
+        ])],
+        read("i"),
+        vec![Op::Putnum],
+    ].concat());
+    assert_eq!(RegisterAllocator.run(code), Op::Let("i".to_string(), vec![Op::PushAddress(Address(8))], vec![
+        Op::Stalloc(1),
+        Op::Getchar,
+        Op::Pop(R0),
+        Op::While(vec![Op::Push(R0)], vec![
+            Op::Let("j".to_string(), vec![Op::PushAddress(Address(9))], vec![
+                Op::Stalloc(1),
+                Op::Push(R0),
+                Op::Pop(R1),
+                Op::Push(R1),
+                Op::Putnum,
+                Op::Stfree(1),
+            ]),
+            Op::Decrement(R0, 1),
+        ]),
+        Op::Push(R0),
+        Op::Putnum,
+    ]));
+
+    // Registers are freed after a variable's last use, so variables that
+    // aren't live at the same time share one, even in nested scopes
+    let mut code = vec![Op::Putnum];
+    for n in (0..6).rev() {
+        let name = format!("x{}", n);
+        code = vec![local(&name, [vec![Op::Stalloc(1), Op::Getchar], write(&name), read(&name), code].concat())];
+    }
+    let allocated = RegisterAllocator.run(code.remove(0)).to_string();
+    assert_eq!(allocated.matches("pop [R0]").count(), 6, "{}", allocated);
+    assert!(!allocated.contains("R1"), "{}", allocated);
+
+    // Sibling scopes reuse the same register
+    let code = Op::Do(vec![
+        local("a", [vec![Op::Stalloc(1), Op::Getchar], write("a"), read("a"), vec![Op::Putnum]].concat()),
+        local("b", [vec![Op::Stalloc(1), Op::Getchar], write("b"), read("b"), vec![Op::Putnum]].concat()),
+    ]);
+    let allocated = RegisterAllocator.run(code).to_string();
+    assert_eq!(allocated.matches("pop [R0]").count(), 2, "{}", allocated);
+    assert!(!allocated.contains("R1"), "{}", allocated);
+
+    // Only five variables that are live at the same time get a register
+    let mut code = [0, 1, 2, 3, 4, 5].map(|n| [read(&format!("x{}", n)), vec![Op::Putnum]].concat()).concat();
+    for n in (0..6).rev() {
+        let name = format!("x{}", n);
+        code = vec![local(&name, [vec![Op::Stalloc(1), Op::Getchar], write(&name), code].concat())];
+    }
+    let allocated = RegisterAllocator.run(code.remove(0)).to_string();
+    for register in ["R0", "R1", "R2", "R3", "R4"] {
+        assert!(allocated.contains(&format!("pop [{}]", register)), "{}", allocated);
+    }
+    // `R5` is kept for `CallIndirect`
+    assert!(!allocated.contains("R5"), "{}", allocated);
+    assert!(allocated.contains("let x5 = (load [FP] %1 0 +)"), "{}", allocated);
+}
+
+#[test]
+fn registers_are_left_to_the_allocator() {
+    // The compiler doesn't use the general purpose registers itself,
+    // so every one of them is free for the register allocator
+    std::thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(|| {
+            let path = "examples/std.hb";
+            let code = std::fs::read_to_string(path).unwrap();
+            let expr = harborc::module::load(path, code).unwrap();
+            let op = expr.check(&std::collections::BTreeMap::new()).unwrap().compile(&mut 0, &mut 0, &harborc::target::Target::default()).unwrap();
+            let op = PassManager::default().run(op);
+            assert!(!op.to_string().contains("[R"));
+            assert!(RegisterAllocator.run(op).to_string().contains("[R0]"));
+        })
+        .unwrap()
+        .join()
+        .unwrap()
+}
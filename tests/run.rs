@@ -0,0 +1,311 @@
+use harborc::{compile_hir, hir, mir, target::*};
+
+/// Compile a program for a target, run it with the interpreter, and return its output
+fn run_on(target: Target, code: impl ToString, input: &'static str) -> String {
+    try_run_on(target, code, input).unwrap()
+}
+
+/// Compile and run a program, and return the error if it stops with one
+fn try_run_on(target: Target, code: impl ToString, input: &'static str) -> Result<String, String> {
+    let code = code.to_string();
+    // Compiling takes more stack than test threads have by default
+    std::thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(move || {
+            let program = match compile_hir("test.hb", code, &target) {
+                Ok(program) => program,
+                Err(e) => panic!("{}", e),
+            };
+            let mut output = vec![];
+            program.run(&target, input.as_bytes(), &mut output)?;
+            Ok(String::from_utf8(output).unwrap())
+        })
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
+/// Compile a program that should be rejected, and return the error
+fn error(code: impl ToString) -> hir::Error {
+    let code = code.to_string();
+    std::thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(move || match compile_hir("test.hb", code, &Target::default()) {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e,
+        })
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
+#[test]
+fn division() {
+    // Division must never count a cell below zero, whatever the target does on overflow
+    let code = r#"
+        fn div(a: int, b: int) -> int = a / b in
+        fn mod(a: int, b: int) -> int = a - a / b * b in
+        fn sdiv(a: signed, b: signed) -> signed = a / b in
+        do
+            putnum(div(1, 10)); putchar(' ');
+            putnum(div(10, 1)); putchar(' ');
+            putnum(div(17, 5)); putchar(' ');
+            putnum(mod(17, 5)); putchar(' ');
+            putnum(div(15, 5)); putchar(' ');
+            putnum(mod(15, 5)); putchar(' ');
+            putnum(div(0, 3)); putchar(' ');
+            putnum(div(250, 7)); putchar(' ');
+            putnum(mod(250, 7)); putchar(' ');
+            putnum(sdiv(4 as signed, -9)); putchar(' ');
+            putnum(sdiv(-17, 5 as signed)); putchar(' ');
+            putnum(sdiv(-8, -2));
+        end
+    "#;
+    for cell_width in [CellWidth::Bits8, CellWidth::Bits16, CellWidth::Bits32, CellWidth::Bits64] {
+        for overflow in [Overflow::Wrap, Overflow::Saturate, Overflow::Trap] {
+            let target = Target::new(cell_width, overflow);
+            assert_eq!(run_on(target, code, ""), "0 10 3 2 3 0 0 35 5 0 -3 4", "on {}", target);
+        }
+    }
+}
+
+#[test]
+fn overflow() {
+    let code = "do putnum(250 + getnum()); putchar(' '); putnum(3 - getnum()); end";
+    assert_eq!(run_on(Target::new(CellWidth::Bits8, Overflow::Wrap), code, "10 5"), "4 254");
+    assert_eq!(run_on(Target::new(CellWidth::Bits8, Overflow::Saturate), code, "10 5"), "255 0");
+    assert_eq!(run_on(Target::new(CellWidth::Bits16, Overflow::Wrap), code, "10 5"), "260 65534");
+    assert!(try_run_on(Target::new(CellWidth::Bits8, Overflow::Trap), code, "10 5").is_err());
+
+    // Literals can use the whole width of a cell
+    assert_eq!(run_on(Target::new(CellWidth::Bits64, Overflow::Wrap), "putnum(5000000000)", ""), "5000000000");
+    assert!(matches!(error("putnum(5000000000)"), hir::Error::LiteralOutOfRange(..)));
+}
+
+#[test]
+fn signed() {
+    let code = r#"
+        fn neg(x: signed) -> signed = x * -1 in
+        let x: signed = -7, y: signed = 3 as signed in do
+            putnum(x + y); putchar(' ');
+            putnum(y - x); putchar(' ');
+            putnum(x * y); putchar(' ');
+            putnum(x / y); putchar(' ');
+            putnum(neg(x) / -2); putchar(' ');
+            putnum(x / neg(y)); putchar(' ');
+            putnum(y / x); putchar(' ');
+            putnum(x + neg(x)); putchar(' ');
+            putnum(neg(0 as signed)); putchar(' ');
+            // There's only one zero, whatever its sign was
+            if neg(0 as signed) == 0 as signed do putchar('1'); end;
+            if x - x == 0 as signed do putchar('1'); end;
+            if x * -1 == 7 as signed do putchar('1'); end;
+        end
+    "#;
+    assert_eq!(run_on(Target::default(), code, ""), "-4 10 -21 -2 -3 2 0 0 0 111");
+}
+
+#[test]
+fn casts() {
+    let code = r#"
+        let p = alloc(2, int) in do
+            putnum('7' as int - '0' as int); putchar(' ');
+            putchar((65 + 1) as char); putchar(' ');
+            putnum(5 as bool as int); putnum(0 as bool as int); putnum(true as int); putchar(' ');
+            putnum(-3 as int); putchar(' ');
+            putnum((12 as signed) * -1); putchar(' ');
+            p[1] = 9;
+            putnum(*((p as int + 1) as &int)); putchar(' ');
+            putnum(((p as &char) as &int == p) as int);
+            free(p);
+        end
+    "#;
+    // Copying a cell takes as long as its value, so negative casts are checked on small cells
+    assert_eq!(run_on(Target::new(CellWidth::Bits16, Overflow::Wrap), code, ""), "7 B 101 65533 -12 9 1");
+    assert_eq!(run_on(Target::new(CellWidth::Bits8, Overflow::Wrap), code, ""), "7 B 101 253 -12 9 1");
+
+    // A negative value cast to `int` overflows the way the target does
+    let code = "let x = getnum(signed) in putnum(x as int)";
+    assert_eq!(run_on(Target::new(CellWidth::Bits8, Overflow::Saturate), code, "-3"), "0");
+    assert!(try_run_on(Target::new(CellWidth::Bits8, Overflow::Trap), code, "-3").is_err());
+    // The magnitude of a `signed` is one cell, however wide that is
+    let code = "let x = getnum(signed) in putnum(x * -1)";
+    assert_eq!(run_on(Target::new(CellWidth::Bits8, Overflow::Wrap), code, "255"), "-255");
+}
+
+#[test]
+fn closures() {
+    let code = r#"
+        let count = 0, step = 5 in
+        fn bump[&count, step]() -> void = count += step in
+        fn scaled[step](n: int) -> int = n * step in do
+            bump();
+            // `step` was captured by value, so changing it doesn't affect the closures
+            step = 100;
+            bump();
+            putnum(count); putchar(' ');
+            putnum(scaled(count)); putchar(' ');
+            putnum(step);
+        end
+    "#;
+    assert_eq!(run_on(Target::default(), code, ""), "10 50 100");
+}
+
+#[test]
+fn generics() {
+    let code = r#"
+        fn swap<T>(a: &T, b: &T) -> void = let tmp = *a in do
+            *a = *b;
+            *b = tmp;
+        end in
+        fn first<A, B>(pair: (A, B)) -> A = pair.0 in
+        fn apply<T>(f: fn(T) -> T, x: T) -> T = f(x) in
+        fn inc(x: int) -> int = x + 1 in
+        fn upper(c: char) -> char = (c as int - 32) as char in
+
+        let x = 1, y = 2, c = 'a', d = 'b', t = (1, 2), u = (3, 4) in do
+            swap(&x, &y);
+            swap(&c, &d);
+            swap(&t, &u);
+            putnum(x); putnum(y); putchar(c); putchar(d); putnum(t.0); putnum(u.1);
+            putchar(' ');
+            putnum(first((5, 'z'))); putchar(first(('q', true)));
+            putnum(apply(inc, apply(inc, 5))); putchar(apply(upper, 'z'));
+        end
+    "#;
+    assert_eq!(run_on(Target::default(), code, ""), "21ba32 5q7Z");
+
+    // Every use of a type parameter must be specialized to the same type
+    let code = r#"
+        fn swap<T>(a: &T, b: &T) -> void = let tmp = *a in do
+            *a = *b;
+            *b = tmp;
+        end in
+        let x = 1, c = 'a' in swap(&x, &c)
+    "#;
+    assert!(matches!(error(code), hir::Error::MismatchedTypes(..)));
+}
+
+#[test]
+fn imports() {
+    let dir = std::env::temp_dir().join(format!("harbor-imports-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, code: &str| std::fs::write(dir.join(name), code).unwrap();
+    write("point.hb", r#"
+        import "shape.hb" as shape;
+        fn name() -> char = 'p';
+        fn move(p: (int, int), dx: int, dy: int) -> (int, int) = (p.0 + dx, p.1 + dy);
+        fn sides() -> int = shape::sides() + 1;
+    "#);
+    write("shape.hb", r#"
+        fn name() -> char = 's';
+        fn sides() -> int = 3;
+    "#);
+    write("a.hb", r#"import "b.hb"; fn a() -> int = 1;"#);
+    write("b.hb", r#"import "a.hb"; fn b() -> int = 2;"#);
+
+    let code = format!(r#"
+        import "{0}/point.hb" as point;
+        import "{0}/shape.hb" as shape;
+        from "{0}/point.hb" import move;
+
+        fn name() -> char = 'm';
+
+        let p = move((1, 2), 3, 4).point::move(1, 1) in do
+            putnum(p.0); putchar(' '); putnum(p.1); putchar(' ');
+            putchar(name()); putchar(point::name()); putchar(shape::name());
+            putnum(point::sides());
+        end
+    "#, dir.display());
+    assert_eq!(run_on(Target::default(), code, ""), "5 7 mps4");
+
+    let code = format!(r#"from "{}/point.hb" import scale; ()"#, dir.display());
+    assert!(matches!(error(code), hir::Error::NotExported(..)));
+    let code = format!(r#"import "{}/a.hb"; putnum(a())"#, dir.display());
+    assert!(matches!(error(code), hir::Error::ImportCycle(..)));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn declaration_order() {
+    // Declarations can use each other before they're written
+    let code = r#"
+        fn main() -> void = do
+            putnum(twice(LIMIT)); putchar(' ');
+            putnum(total);
+        end;
+        let total: int = twice(ONE) + LIMIT;
+        fn twice(n: int) -> int = add(n, n);
+        const LIMIT: int = ONE * 4;
+        fn add(a: int, b: int) -> int = a + b;
+        const ONE: int = 1;
+    "#;
+    assert_eq!(run_on(Target::default(), code, ""), "8 6");
+
+    let code = r#"
+        fn even(n: int) -> bool = n == 0 || odd(n - 1);
+        fn odd(n: int) -> bool = n != 0 && even(n - 1);
+        fn main() -> void = putnum(even(4) as int);
+    "#;
+    assert!(matches!(error(code), hir::Error::RecursiveDefinition(..)));
+    let code = r#"
+        const A: int = B + 1;
+        const B: int = A + 1;
+        fn main() -> void = putnum(A);
+    "#;
+    assert!(matches!(error(code), hir::Error::RecursiveDefinition(..)));
+}
+
+#[test]
+fn folding() {
+    // Constant expressions are folded the way the target would compute them
+    let code = r#"
+        fn add(a: int, b: int) -> int = a + b;
+        fn sub(a: int, b: int) -> int = a - b;
+        fn mul(a: int, b: int) -> int = a * b;
+
+        fn main() -> void = do
+            putnum(250 + 10); putchar(' '); putnum(add(250, 10)); putchar(' ');
+            putnum(3 - 5); putchar(' '); putnum(sub(3, 5)); putchar(' ');
+            putnum(20 * 20); putchar(' '); putnum(mul(20, 20));
+        end;
+    "#;
+    assert_eq!(run_on(Target::new(CellWidth::Bits8, Overflow::Wrap), code, ""), "4 4 254 254 144 144");
+    assert_eq!(run_on(Target::new(CellWidth::Bits8, Overflow::Saturate), code, ""), "255 255 0 0 255 255");
+    // Overflow isn't folded away on a target that traps
+    assert!(try_run_on(Target::new(CellWidth::Bits8, Overflow::Trap), code, "").is_err());
+    assert!(try_run_on(Target::new(CellWidth::Bits16, Overflow::Trap), "fn main() -> void = putnum(3 - 5);", "").is_err());
+}
+
+#[test]
+fn function_values() {
+    let code = r#"
+        fn inc(x: int) -> int = x + 1;
+        fn main() -> void = let f = inc in
+            fn double(x: int) -> int = x * 2 in do
+                putnum(f(5));
+                f = double;
+                putnum(f(5));
+            end;
+    "#;
+    assert_eq!(run_on(Target::default(), code, ""), "610");
+
+    // Function values can't be called outside of the scope they're defined in
+    let code = "fn main() -> void = let g = (fn inc(x: int) -> int = x + 1 in inc) in putnum(g(5));";
+    assert!(matches!(error(code), hir::Error::FunctionEscapes(..)));
+    let code = r#"
+        fn main() -> void = let p = alloc(1, fn(int) -> int) in do
+            fn inc(x: int) -> int = x + 1 in p[0] = inc;
+            let g = p[0] in putnum(g(5));
+        end;
+    "#;
+    let mut e = match error(code) {
+        hir::Error::MIRError(e) => e,
+        e => panic!("expected a MIR error, found {:?}", e),
+    };
+    while let mir::Error::Within(_, inner) = e {
+        e = *inner;
+    }
+    assert_eq!(e, mir::Error::NoCallTarget("(int, ) -> int".to_string()));
+}
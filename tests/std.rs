@@ -0,0 +1,228 @@
+use harborc::{compile_hir, target::Target};
+
+/// Compile a program, run it with the interpreter, and return its output
+fn run(code: &'static str, input: &'static str) -> String {
+    // Compiling takes more stack than test threads have by default
+    std::thread::Builder::new()
+        .stack_size(64 * 1024 * 1024)
+        .spawn(move || {
+            let target = Target::default();
+            let program = match compile_hir("test.hb", code, &target) {
+                Ok(program) => program,
+                Err(e) => panic!("{}", e),
+            };
+            let mut output = vec![];
+            program.run(&target, input.as_bytes(), &mut output).unwrap();
+            String::from_utf8(output).unwrap()
+        })
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
+#[test]
+fn strings() {
+    let code = r#"
+        import "std/str.hb";
+
+        let buf = alloc(16, char) in do
+            strcpy(buf, "port");
+            strcat(buf, "able");
+            putstrln(buf);
+            putnum(strlen(buf));
+            putchar(' ');
+            putnum(streq(buf, "portable") as int);
+            putnum(streq(buf, "port") as int);
+            free(buf);
+        end
+    "#;
+    assert_eq!(run(code, ""), "portable\n8 10");
+}
+
+#[test]
+fn numbers() {
+    let code = r#"
+        import "std/num.hb";
+
+        let buf = alloc(8, char) in do
+            putnumln(parse("4096") + parse("4"));
+            numstr(1023, buf);
+            putchar(buf[0]); putchar(buf[3]); putnum(buf[4] as int);
+            free(buf);
+        end
+    "#;
+    assert_eq!(run(code, ""), "4100\n130");
+}
+
+#[test]
+fn memory() {
+    let code = r#"
+        let a = alloc(3, (int, bool)), b = alloc(3, (int, bool)) in do
+            memset(a, (5, true), 3);
+            a[1] = (6, false);
+            memcpy(b, a, 3);
+            putnum(b[0].0); putnum(b[1].0); putnum(b[2].1 as int);
+
+            // Growing keeps the contents, and shrinking keeps what fits
+            let c = realloc(b, 5) in do
+                c[4] = (9, true);
+                putnum(c[2].0); putnum(c[4].0);
+                let d = realloc(c, 2) in do putnum(d[1].0); free(d) end
+            end;
+            putnum(sizeof((int, (char, signed), bool)));
+            free(a);
+        end
+    "#;
+    assert_eq!(run(code, ""), "5615965");
+
+    let code = r#"
+        import "std/mem.hb";
+        import "std/str.hb";
+
+        fn main() -> void = let a = memfill('z', 4), b = memdup("harbor", 7) in do
+            a[3] = '\0';
+            putstr(a); putstr(b);
+            memcpy(b, "ship", 4);
+            putstr(b);
+            // Static data isn't an allocation, so reallocating it doesn't keep anything
+            let c = realloc("ahoy", 2) in do putnum(c[0] as int); free(c) end;
+            free(a); free(b);
+        end;
+    "#;
+    assert_eq!(run(code, ""), "zzzharborshipor0");
+
+    // The built in functions can still be imported from the file that used to define them
+    let code = r#"
+        from "std/mem.hb" import memcpy, memfill;
+
+        fn main() -> void = let a = memfill(7, 2), b = alloc(2, int) in do
+            memcpy(b, a, 2);
+            putnum(b[0] + b[1]);
+            free(a); free(b);
+        end;
+    "#;
+    assert_eq!(run(code, ""), "14");
+
+    // An empty allocation is the null address, which can be freed or grown
+    let code = r#"
+        fn main() -> void = let e = alloc(0, int) in do
+            putnum(e as int);
+            free(alloc(0, char));
+            let f = realloc(e, 2) in do f[1] = 4; putnum(f[1]); free(f) end;
+            putnum(realloc(alloc(1, int), 0) as int);
+        end;
+    "#;
+    assert_eq!(run(code, ""), "040");
+}
+
+#[test]
+fn input() {
+    let code = r#"
+        from "std/io.hb" import input, putln;
+        import "std/str.hb";
+
+        let buf = alloc(16, char) in do
+            input(buf, 16);
+            putstr(buf); putln();
+            input(buf, 4);
+            putstr(buf); putln();
+            input(buf, 16);
+            putstr(buf); putln();
+            input(buf, 16);
+            putstr(buf); putln();
+            input(buf, 16);
+            putnum(strlen(buf));
+            free(buf);
+        end
+    "#;
+    // The input ends without a newline, and is read past its end
+    assert_eq!(run(code, "first\nsecond\nlast"), "first\nsec\nond\nlast\n0");
+}
+
+#[test]
+fn static_data() {
+    let code = r#"
+        import "std/str.hb";
+
+        fn main() -> void = do
+            let i = 0 in while i != 3 do
+                // The literal is stored once, and not allocated each time
+                total += strlen("ahoy");
+                i = i + 1
+            end;
+            putnum(total); putchar(' ');
+            putnum(PRIMES[3]); putchar(pairs[1].1);
+        end;
+
+        let total: int = 0;
+        const PRIMES: &int = [2, 3, 5, 7];
+        let pairs: &(int, char) = [(1, 'a'), (2, 'b')];
+    "#;
+    assert_eq!(run(code, ""), "12 7b");
+}
+
+#[test]
+fn globals() {
+    let code = r#"
+        fn main() -> void = do
+            move(3); move(4); hits++; hits++; hits--;
+            putnum(pos.0); putchar(' '); putnum(pos.1); putchar(' ');
+            putnum(hits); putchar(pos.2);
+        end;
+
+        global pos: (int, int, char) = (1, 2, 'x');
+        global hits: int = 0;
+        fn move(d: int) -> void = pos = (pos.0 + d, pos.1 * d, pos.2);
+    "#;
+    assert_eq!(run(code, ""), "8 24 1x");
+}
+
+#[test]
+fn patterns() {
+    let code = r#"
+        fn swap((a, b): (int, char)) -> (char, int) = (b, a);
+        fn sum(((a, b), c): ((int, int), int), d: int) -> int = a + b + c + d;
+
+        fn main() -> void = do
+            let (c, n) = swap((3, 'z')) in do putchar(c); putnum(n) end;
+            // The value is evaluated before the new names are in scope
+            let x = 5, y = 6 in let (x, y) = (y, x) in do putnum(x); putnum(y) end;
+            putnum(sum(((1, 2), 3), 4));
+        end;
+    "#;
+    assert_eq!(run(code, ""), "z36510");
+}
+
+#[test]
+fn tuple_equality() {
+    let code = r#"
+        fn main() -> void = do
+            let p = (1, 'a', (2 as signed, true)), q = (1, 'a', (-2, true)) in do
+                putnum((p == p) as int); putnum((p == q) as int); putnum((p != q) as int);
+            end;
+            let a = alloc(1, (int, int)) in do
+                *a = (3, 4);
+                putnum((*a == (3, 4)) as int); putnum((*a != (3, 4)) as int);
+                free(a)
+            end
+        end;
+    "#;
+    assert_eq!(run(code, ""), "10110");
+}
+
+#[test]
+fn short_circuit() {
+    let code = r#"
+        fn t() -> bool = do putchar('t'); true end;
+        fn f() -> bool = do putchar('f'); false end;
+
+        fn main() -> void = do
+            putnum((t() || t()) as int); putnum((f() && t()) as int);
+            putnum((t() && f()) as int); putnum((f() || t()) as int);
+            let x = 5 in putnum(((x != 0) == true) as int);
+            let q = 0 as &int in if q != (0 as &int) && *q == 1 do putchar('x') end;
+        end;
+    "#;
+    assert_eq!(run(code, ""), "t1f0tf0ft11");
+}
+